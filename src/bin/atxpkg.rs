@@ -1,8 +1,10 @@
-use std::{path::Path, process::ExitCode};
+use std::io::Write;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 
 use atxpkg::*;
-use clap::{Args, Parser, Subcommand};
-use itertools::Itertools;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 #[derive(Debug, Parser)]
 #[command(version = env!("CARGO_PKG_VERSION"))]
@@ -19,6 +21,19 @@ struct MainArgs {
     /// Enable debug mode.
     #[arg(long, default_value = "false")]
     debug: bool,
+    /// Wait for other running atxpkg instead of failing.
+    #[arg(long, default_value = "false")]
+    wait: bool,
+    /// Output format, with json query results and plans are printed as json and progress
+    /// messages go to stderr.
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    format: OutputFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
 }
 
 #[derive(Debug, Subcommand)]
@@ -31,6 +46,27 @@ enum Command {
     Upstall(InstallArgs),
     /// Remove packages.
     Remove(InstallArgs),
+    /// Show what update of packages would change (without changing anything).
+    Diff(DiffArgs),
+    /// Return packages to the version they had before their last update.
+    Rollback(RollbackArgs),
+    /// Write installed packages (with versions, holds and channels) to a manifest file.
+    Export(ExportArgs),
+    /// Install, update, downgrade (and optionally remove) packages to match a manifest file.
+    Sync(SyncArgs),
+    /// List transactions (or show details of one).
+    History(HistoryArgs),
+    /// Revert changes done by a transaction from history.
+    Undo(UndoArgs),
+    /// Hold packages at their current version (or up to given version).
+    Hold(HoldArgs),
+    /// Release hold of packages.
+    Unhold(UnholdArgs),
+    /// Switch packages (or globally if no packages given) to release channel (stable or dev).
+    #[command(alias = "switch_channel")]
+    SwitchChannel(SwitchChannelArgs),
+    /// Finish operation interrupted by a crash (done automatically on start).
+    Recover,
     /// Check packages.
     Check(CheckArgs),
     /// List available packages.
@@ -42,18 +78,153 @@ enum Command {
     /// Show untracked files.
     #[command(name = "show_untracked")]
     ShowUntracked(ShowUntrackedArgs),
+    /// Show which installed package owns given paths.
+    Owns(OwnsArgs),
+    /// Show details of installed or available package.
+    Info(InfoArgs),
+    /// List files of installed or available package (or local package file).
+    Files(FilesArgs),
     /// Clean cache.
     #[command(name = "clean_cache")]
     CleanCache,
 }
 
+impl Command {
+    /// Operation settings given on the command line.
+    fn options(&self) -> Options {
+        match self {
+            Command::Install(args)
+            | Command::Update(args)
+            | Command::Upstall(args)
+            | Command::Remove(args) => Options {
+                overwrite: args.overwrite(),
+                reinstall: args.reinstall(),
+                adopt: args.adopt,
+                ignore_hold: args.ignore_hold,
+                download_only: args.downloadonly,
+                yes: args.yes,
+                no: args.no,
+                offline: args.offline,
+                unverified_ssl: args.unverified_ssl,
+                ..Options::default()
+            },
+            Command::Diff(args) => Options {
+                overwrite: args.overwrite.clone(),
+                ignore_hold: args.ignore_hold,
+                offline: args.offline,
+                unverified_ssl: args.unverified_ssl,
+                ..Options::default()
+            },
+            Command::Rollback(RollbackArgs {
+                overwrite,
+                yes,
+                no,
+                offline,
+                unverified_ssl,
+                ..
+            })
+            | Command::Sync(SyncArgs {
+                overwrite,
+                yes,
+                no,
+                offline,
+                unverified_ssl,
+                ..
+            })
+            | Command::Undo(UndoArgs {
+                overwrite,
+                yes,
+                no,
+                offline,
+                unverified_ssl,
+                ..
+            }) => Options {
+                overwrite: overwrite.clone(),
+                yes: *yes,
+                no: *no,
+                offline: *offline,
+                unverified_ssl: *unverified_ssl,
+                ..Options::default()
+            },
+            Command::SwitchChannel(args) => Options {
+                overwrite: args.overwrite.clone(),
+                ignore_hold: args.ignore_hold,
+                yes: args.yes,
+                no: args.no,
+                offline: args.offline,
+                unverified_ssl: args.unverified_ssl,
+                ..Options::default()
+            },
+            Command::Check(args) => Options {
+                full: args.full,
+                repair: args.repair,
+                offline: args.offline,
+                unverified_ssl: args.unverified_ssl,
+                ..Options::default()
+            },
+            Command::ListAvailable(ListAvailableArgs {
+                offline,
+                unverified_ssl,
+                ..
+            })
+            | Command::Info(InfoArgs {
+                offline,
+                unverified_ssl,
+                ..
+            })
+            | Command::Files(FilesArgs {
+                offline,
+                unverified_ssl,
+                ..
+            }) => Options {
+                offline: *offline,
+                unverified_ssl: *unverified_ssl,
+                ..Options::default()
+            },
+            _ => Options::default(),
+        }
+    }
+
+    /// Read-only commands can run alongside each other, the rest needs the root dir for itself.
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Diff(_)
+                | Command::Export(_)
+                | Command::History(_)
+                | Command::Check(CheckArgs { repair: false, .. })
+                | Command::ListAvailable(_)
+                | Command::ListInstalled
+                | Command::ShowUntracked(_)
+                | Command::Owns(_)
+                | Command::Info(_)
+                | Command::Files(_)
+        )
+    }
+}
+
 #[derive(Args, Debug)]
 struct InstallArgs {
     /// Packages
     packages: Vec<String>,
-    /// Force operation (overwrite files etc.)
+    /// Read package specs from file (one per line, blank lines and # comments ignored, can be repeated).
+    #[arg(short = 'r', long, value_name = "FILE")]
+    from_file: Vec<String>,
+    /// Force operation (same as `--overwrite '*' --reinstall`).
     #[arg(short = 'f', long, default_value = "false")]
     force: bool,
+    /// Allow overwriting untracked files matching the glob (relative to prefix, can be repeated).
+    #[arg(long, value_name = "GLOB")]
+    overwrite: Vec<String>,
+    /// Reinstall/update packages even if the same version is already installed.
+    #[arg(long, default_value = "false")]
+    reinstall: bool,
+    /// Update held packages anyway (only for packages named explicitly).
+    #[arg(long, default_value = "false")]
+    ignore_hold: bool,
+    /// Take ownership of already existing files instead of failing (changed backup files get the new version as .atxpkg_new).
+    #[arg(long, default_value = "false")]
+    adopt: bool,
     /// Only download packages, don't install/update anything.
     #[arg(short = 'w', long, default_value = "false")]
     downloadonly: bool,
@@ -74,6 +245,52 @@ struct InstallArgs {
     unverified_ssl: bool,
 }
 
+impl InstallArgs {
+    fn overwrite(&self) -> Vec<String> {
+        if self.force {
+            return vec!["*".to_string()];
+        }
+        self.overwrite.clone()
+    }
+
+    fn reinstall(&self) -> bool {
+        self.force || self.reinstall
+    }
+
+    /// Positional package specs followed by those from `--from-file` files.
+    fn packages(&self) -> anyhow::Result<Vec<String>> {
+        let mut packages = self.packages.clone();
+        for fn_ in &self.from_file {
+            let specs = read_lines(fn_)
+                .map_err(|err| anyhow::anyhow!("failed to read package list {fn_}: {err}"))?;
+            packages.extend(specs);
+        }
+        if packages.is_empty() && !self.from_file.is_empty() {
+            // don't let an empty list turn into "all packages"
+            anyhow::bail!("no packages listed in {}", self.from_file.join(", "));
+        }
+        Ok(packages)
+    }
+}
+
+#[derive(Args, Debug)]
+struct DiffArgs {
+    /// Packages
+    packages: Vec<String>,
+    /// Allow overwriting untracked files matching the glob (relative to prefix, can be repeated).
+    #[arg(long, value_name = "GLOB")]
+    overwrite: Vec<String>,
+    /// Update held packages anyway (only for packages named explicitly).
+    #[arg(long, default_value = "false")]
+    ignore_hold: bool,
+    /// Don't connect to online repositories.
+    #[arg(long, default_value = "false")]
+    offline: bool,
+    /// Don't verify ssl certificate validity.
+    #[arg(long, default_value = "false")]
+    unverified_ssl: bool,
+}
+
 #[derive(Args, Debug)]
 struct ListAvailableArgs {
     /// Packages
@@ -90,6 +307,18 @@ struct ListAvailableArgs {
 struct CheckArgs {
     /// Packages
     packages: Vec<String>,
+    /// Hash all files instead of only those whose size or mtime changed since install.
+    #[arg(long, default_value = "false")]
+    full: bool,
+    /// Restore missing and corrupted files (except for backup ones) from the installed package version.
+    #[arg(long, default_value = "false")]
+    repair: bool,
+    /// Don't connect to online repositories.
+    #[arg(long, default_value = "false")]
+    offline: bool,
+    /// Don't verify ssl certificate validity.
+    #[arg(long, default_value = "false")]
+    unverified_ssl: bool,
 }
 
 #[derive(Args, Debug)]
@@ -98,6 +327,170 @@ struct ShowUntrackedArgs {
     paths: Vec<String>,
 }
 
+#[derive(Args, Debug)]
+struct InfoArgs {
+    /// Package
+    package: String,
+    /// Don't connect to online repositories.
+    #[arg(long, default_value = "false")]
+    offline: bool,
+    /// Don't verify ssl certificate validity.
+    #[arg(long, default_value = "false")]
+    unverified_ssl: bool,
+}
+
+#[derive(Args, Debug)]
+struct FilesArgs {
+    /// Package or path to package file
+    package: String,
+    /// Don't connect to online repositories.
+    #[arg(long, default_value = "false")]
+    offline: bool,
+    /// Don't verify ssl certificate validity.
+    #[arg(long, default_value = "false")]
+    unverified_ssl: bool,
+}
+
+#[derive(Args, Debug)]
+struct RollbackArgs {
+    /// Packages
+    #[arg(required = true)]
+    packages: Vec<String>,
+    /// Allow overwriting untracked files matching the glob (relative to prefix, can be repeated).
+    #[arg(long, value_name = "GLOB")]
+    overwrite: Vec<String>,
+    /// Automatically answer yes to all questions.
+    #[arg(short = 'y', long, default_value = "false")]
+    yes: bool,
+    /// Automatically answer no to all questions.
+    #[arg(short = 'n', long, default_value = "false")]
+    no: bool,
+    /// Don't connect to online repositories.
+    #[arg(long, default_value = "false")]
+    offline: bool,
+    /// Don't verify ssl certificate validity.
+    #[arg(long, default_value = "false")]
+    unverified_ssl: bool,
+}
+
+#[derive(Args, Debug)]
+struct ExportArgs {
+    /// Manifest file
+    manifest: String,
+}
+
+#[derive(Args, Debug)]
+struct SyncArgs {
+    /// Manifest file
+    manifest: String,
+    /// Remove installed packages missing in the manifest.
+    #[arg(long, default_value = "false")]
+    remove: bool,
+    /// Allow overwriting untracked files matching the glob (relative to prefix, can be repeated).
+    #[arg(long, value_name = "GLOB")]
+    overwrite: Vec<String>,
+    /// Automatically answer yes to all questions.
+    #[arg(short = 'y', long, default_value = "false")]
+    yes: bool,
+    /// Automatically answer no to all questions.
+    #[arg(short = 'n', long, default_value = "false")]
+    no: bool,
+    /// Don't connect to online repositories.
+    #[arg(long, default_value = "false")]
+    offline: bool,
+    /// Don't verify ssl certificate validity.
+    #[arg(long, default_value = "false")]
+    unverified_ssl: bool,
+}
+
+#[derive(Args, Debug)]
+struct HistoryArgs {
+    /// Transaction id
+    id: Option<u64>,
+}
+
+#[derive(Args, Debug)]
+struct UndoArgs {
+    /// Transaction id
+    id: u64,
+    /// Allow overwriting untracked files matching the glob (relative to prefix, can be repeated).
+    #[arg(long, value_name = "GLOB")]
+    overwrite: Vec<String>,
+    /// Automatically answer yes to all questions.
+    #[arg(short = 'y', long, default_value = "false")]
+    yes: bool,
+    /// Automatically answer no to all questions.
+    #[arg(short = 'n', long, default_value = "false")]
+    no: bool,
+    /// Don't connect to online repositories.
+    #[arg(long, default_value = "false")]
+    offline: bool,
+    /// Don't verify ssl certificate validity.
+    #[arg(long, default_value = "false")]
+    unverified_ssl: bool,
+}
+
+#[derive(Args, Debug)]
+struct HoldArgs {
+    /// Packages
+    #[arg(required = true)]
+    packages: Vec<String>,
+    /// Allow updates up to this version (inclusive) instead of no updates at all.
+    #[arg(long, value_name = "VERSION")]
+    max_version: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct UnholdArgs {
+    /// Packages
+    #[arg(required = true)]
+    packages: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct SwitchChannelArgs {
+    /// Channel (stable or dev)
+    channel: String,
+    /// Packages
+    packages: Vec<String>,
+    /// Allow overwriting untracked files matching the glob (relative to prefix, can be repeated).
+    #[arg(long, value_name = "GLOB")]
+    overwrite: Vec<String>,
+    /// Switch held packages anyway.
+    #[arg(long, default_value = "false")]
+    ignore_hold: bool,
+    /// Automatically answer yes to all questions.
+    #[arg(short = 'y', long, default_value = "false")]
+    yes: bool,
+    /// Automatically answer no to all questions.
+    #[arg(short = 'n', long, default_value = "false")]
+    no: bool,
+    /// Don't connect to online repositories.
+    #[arg(long, default_value = "false")]
+    offline: bool,
+    /// Don't verify ssl certificate validity.
+    #[arg(long, default_value = "false")]
+    unverified_ssl: bool,
+}
+
+#[derive(Args, Debug)]
+struct OwnsArgs {
+    /// Paths (absolute or relative to prefix)
+    #[arg(required = true)]
+    paths: Vec<String>,
+}
+
+fn format_timestamp(t: f64) -> String {
+    let Ok(dt) = time::OffsetDateTime::from_unix_timestamp(t as i64) else {
+        return t.to_string();
+    };
+    let dt = dt.to_offset(time::UtcOffset::current_local_offset().unwrap_or(time::UtcOffset::UTC));
+    dt.format(time::macros::format_description!(
+        "[year]-[month]-[day] [hour]:[minute]:[second]"
+    ))
+    .unwrap_or_else(|_| t.to_string())
+}
+
 // TODO: cut-n-pasted from router and modified - unite!
 fn log_init(fn_: Option<&str>, level: Option<&str>, show: bool) -> anyhow::Result<()> {
     let log_level_term = if let Some(level) = level {
@@ -152,12 +545,204 @@ fn log_init(fn_: Option<&str>, level: Option<&str>, show: bool) -> anyhow::Resul
     Ok(())
 }
 
+/// Fails unless all packages listed in `--if-installed` are installed.
+fn check_if_installed(args: &InstallArgs, atxpkg: &Atxpkg) -> anyhow::Result<()> {
+    if let Some(if_installed_) = &args.if_installed {
+        if_installed(
+            if_installed_.split(',').map(|x| x.to_string()).collect(),
+            &atxpkg.installed_packages()?,
+        )?;
+    }
+    Ok(())
+}
+
+/// Reports to the terminal: progress bars (and messages printed along them) on stderr, other
+/// messages and plans on stdout (messages go to stderr with json output so that stdout stays
+/// parseable), confirmations read from stdin.
+struct CliReporter {
+    json: bool,
+}
+
+impl CliReporter {
+    fn make_progress_bar(
+        prefix: &str,
+        action: &str,
+        unit: ProgressUnit,
+        len: u64,
+    ) -> indicatif::ProgressBar {
+        let template = match unit {
+            ProgressUnit::Bytes => {
+                "{spinner} {prefix} [{wide_bar}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})"
+            }
+            ProgressUnit::Items => "{spinner} {prefix} [{wide_bar}] {pos}/{len}",
+        };
+        let prefix = match action {
+            "" => prefix.to_string(),
+            _ => format!("{prefix}: {action}"),
+        };
+        let progress_bar = indicatif::ProgressBar::new(len).with_prefix(prefix);
+        progress_bar.set_style(
+            indicatif::ProgressStyle::default_bar()
+                .template(template)
+                .unwrap()
+                .tick_chars(r"|/-\ ")
+                .progress_chars("##-"),
+        );
+        // keep spinning while the length is unknown (listing or download not started yet)
+        if len == 0 {
+            progress_bar.enable_steady_tick(Duration::from_millis(200));
+        }
+        progress_bar
+    }
+}
+
+struct CliProgress {
+    progress_bar: indicatif::ProgressBar,
+    /// Bars of a group share the line break printed when the group finishes.
+    in_group: bool,
+}
+
+impl Progress for CliProgress {
+    fn set_length(&self, len: u64) {
+        self.progress_bar.set_length(len);
+    }
+
+    fn set_position(&self, pos: u64) {
+        self.progress_bar.set_position(pos);
+        self.progress_bar.reset_eta();
+    }
+
+    fn inc(&self, delta: u64) {
+        self.progress_bar.inc(delta);
+    }
+
+    fn println(&self, msg: &str) {
+        self.progress_bar.suspend(|| eprintln!("{msg}"));
+    }
+
+    fn finish(&self) {
+        self.progress_bar.finish();
+        if !self.in_group {
+            eprintln!();
+        }
+    }
+}
+
+struct CliProgressGroup {
+    multi_progress: indicatif::MultiProgress,
+}
+
+impl ProgressGroup for CliProgressGroup {
+    fn progress(
+        &self,
+        prefix: &str,
+        action: &str,
+        unit: ProgressUnit,
+        len: u64,
+    ) -> Box<dyn Progress> {
+        let progress_bar = CliReporter::make_progress_bar(prefix, action, unit, len);
+        Box::new(CliProgress {
+            progress_bar: self.multi_progress.add(progress_bar),
+            in_group: true,
+        })
+    }
+
+    fn finish(&self) {
+        eprintln!();
+    }
+}
+
+impl Reporter for CliReporter {
+    fn progress(
+        &self,
+        prefix: &str,
+        action: &str,
+        unit: ProgressUnit,
+        len: u64,
+    ) -> Box<dyn Progress> {
+        Box::new(CliProgress {
+            progress_bar: Self::make_progress_bar(prefix, action, unit, len),
+            in_group: false,
+        })
+    }
+
+    fn progress_group(&self) -> Box<dyn ProgressGroup> {
+        Box::new(CliProgressGroup {
+            multi_progress: indicatif::MultiProgress::new(),
+        })
+    }
+
+    fn message(&self, msg: &str) {
+        match self.json {
+            true => eprintln!("{msg}"),
+            false => println!("{msg}"),
+        }
+    }
+
+    fn warning(&self, msg: &str) {
+        eprintln!("{msg}");
+    }
+
+    fn plan(&self, plan: &[PlannedChange]) {
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(plan).unwrap());
+        } else {
+            for change in plan {
+                println!("{change}");
+            }
+        }
+    }
+
+    fn check_report(&self, report: &CheckReport) {
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(report).unwrap());
+        } else {
+            for issue in &report.issues {
+                println!("{issue}");
+            }
+            if !report.issues.is_empty() {
+                println!("{}", report.summary);
+            }
+        }
+    }
+
+    fn confirm(&self, prompt: &str, default: bool) -> anyhow::Result<bool> {
+        let question = match default {
+            true => format!("{prompt} [Y/n] "),
+            false => format!("{prompt} [y/N] "),
+        };
+
+        loop {
+            if self.json {
+                eprint!("{question}");
+                std::io::stderr().flush()?;
+            } else {
+                print!("{question}");
+                std::io::stdout().flush()?;
+            }
+
+            let mut ans = String::new();
+            std::io::stdin().read_line(&mut ans)?;
+            match ans.trim().to_lowercase().as_str() {
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                "" => return Ok(default),
+                _ => self.message("Invalid input. Please enter 'y' or 'n'."),
+            }
+        }
+    }
+}
+
 fn main_sub() -> anyhow::Result<u8> {
     unsafe {
         std::env::set_var("RUST_BACKTRACE", "1");
     }
 
     let mainargs = MainArgs::parse();
+    let json = mainargs.format == OutputFormat::Json;
+    let reporter: Arc<dyn Reporter> = Arc::new(CliReporter { json });
+    // messages of the binary itself and of the free functions it calls
+    set_reporter(reporter.clone());
 
     #[cfg(target_os = "linux")]
     let root_dir = "/tmp/atxpkg";
@@ -173,210 +758,337 @@ fn main_sub() -> anyhow::Result<u8> {
 
     log::debug!("args: {mainargs:#?}");
 
-    if !Path::new(root_dir).exists() {
-        return Err(anyhow::anyhow!("root dir {root_dir} does not exist"));
-    }
-    if !Path::new(&mainargs.prefix).exists() {
-        anyhow::bail!("prefix dir {} does not exist", &mainargs.prefix);
-    }
-
-    let cache_dir = format!("{root_dir}/cache");
-    let tmp_dir_prefix = format!("{root_dir}/tmp");
-    let db_fn = format!("{root_dir}/installed.json");
-    let repos_fn = format!("{root_dir}/repos.txt");
-
-    if !Path::new(&cache_dir).exists() {
-        log::info!("creating cache dir {cache_dir}");
-        std::fs::create_dir(&cache_dir)?;
-    }
-    if !Path::new(&tmp_dir_prefix).exists() {
-        log::info!("creating tmp dir {tmp_dir_prefix}");
-        std::fs::create_dir(&tmp_dir_prefix)?;
-    }
-
-    let mut repos = vec![cache_dir.clone()];
-    if Path::new(&repos_fn).exists() {
-        for line in std::fs::read_to_string(&repos_fn)?.lines() {
-            let line = line.trim();
-            if !line.is_empty() && !line.starts_with('#') {
-                repos.push(line.to_string());
-            }
-        }
-    }
-    log::debug!("repos: {repos:?}");
+    let atxpkg = Atxpkg::builder(root_dir)
+        .prefix(&mainargs.prefix)
+        .read_only(mainargs.command.is_read_only())
+        .wait(mainargs.wait)
+        .options(mainargs.command.options())
+        .reporter(reporter)
+        .open()?;
 
     match &mainargs.command {
         Command::Install(args) => {
-            let mut installed_packages = get_installed_packages(&db_fn)?;
-            if let Some(if_installed_) = &args.if_installed {
-                if_installed(
-                    if_installed_.split(',').map(|x| x.to_string()).collect(),
-                    &installed_packages,
-                )?;
-            }
-            let res = install_packages(
-                args.packages.to_vec(),
-                &mut installed_packages,
-                &mainargs.prefix,
-                repos,
-                args.force,
-                args.offline,
-                args.yes,
-                args.no,
-                args.downloadonly,
-                args.unverified_ssl,
-                &cache_dir,
-                &tmp_dir_prefix,
-            );
-            save_installed_packages(&installed_packages, &db_fn)?;
-            if res? {
+            let packages = args.packages()?;
+            check_if_installed(args, &atxpkg)?;
+            if atxpkg.install(packages)? {
                 log::info!("install completed");
-                println!("install completed");
+                status!("install completed");
             }
         }
         Command::Update(args) => {
-            let mut installed_packages = get_installed_packages(&db_fn)?;
-            if let Some(if_installed_) = &args.if_installed {
-                if_installed(
-                    if_installed_.split(',').map(|x| x.to_string()).collect(),
-                    &installed_packages,
-                )?;
-            }
-            let packages = if args.packages.is_empty() {
-                installed_packages
-                    .keys()
-                    .map(|x| x.to_string())
-                    .sorted()
-                    .collect()
-            } else {
-                args.packages.to_vec()
-            };
-            let res = update_packages(
-                packages,
-                &mut installed_packages,
-                &mainargs.prefix,
-                repos,
-                args.force,
-                args.offline,
-                args.yes,
-                args.no,
-                args.downloadonly,
-                args.unverified_ssl,
-                &cache_dir,
-                &tmp_dir_prefix,
-            );
-            save_installed_packages(&installed_packages, &db_fn)?;
-            if res? {
+            let packages = args.packages()?;
+            check_if_installed(args, &atxpkg)?;
+            if atxpkg.update(packages)? {
                 log::info!("update completed");
-                println!("update completed");
+                status!("update completed");
             }
         }
         Command::Upstall(args) => {
-            let mut installed_packages = get_installed_packages(&db_fn)?;
-            if let Some(if_installed_) = &args.if_installed {
-                if_installed(
-                    if_installed_.split(',').map(|x| x.to_string()).collect(),
-                    &installed_packages,
-                )?;
-            }
-            let res = upstall_packages(
-                args.packages.to_vec(),
-                &mut installed_packages,
-                &mainargs.prefix,
-                repos,
-                args.force,
-                args.offline,
-                args.yes,
-                args.no,
-                args.downloadonly,
-                args.unverified_ssl,
-                &cache_dir,
-                &tmp_dir_prefix,
-            );
-            save_installed_packages(&installed_packages, &db_fn)?;
-            if res? {
+            let packages = args.packages()?;
+            check_if_installed(args, &atxpkg)?;
+            if atxpkg.upstall(packages)? {
                 log::info!("upstall completed");
-                println!("upstall completed");
+                status!("upstall completed");
             }
         }
         Command::Remove(args) => {
-            let mut installed_packages = get_installed_packages(&db_fn)?;
-            if let Some(if_installed_) = &args.if_installed {
-                if_installed(
-                    if_installed_.split(',').map(|x| x.to_string()).collect(),
-                    &installed_packages,
-                )?;
-            }
-            let res = remove_packages(
-                args.packages.to_vec(),
-                &mut installed_packages,
-                &mainargs.prefix,
-                args.yes,
-                args.no,
-            );
-            save_installed_packages(&installed_packages, &db_fn)?;
-            if res? {
+            let packages = args.packages()?;
+            check_if_installed(args, &atxpkg)?;
+            if atxpkg.remove(packages)? {
                 log::info!("remove completed");
-                println!("remove completed");
+                status!("remove completed");
+            }
+        }
+        Command::Diff(args) => {
+            let previews = atxpkg.preview_update(args.packages.to_vec())?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&previews)?);
+                return Ok(0);
+            }
+            if previews.is_empty() {
+                println!("nothing to update");
+            }
+            for preview in previews {
+                println!(
+                    "update {}-{} -> {}-{}",
+                    preview.name_old, preview.version_old, preview.name_new, preview.version_new
+                );
+                for (fn_, action) in preview.files {
+                    match action {
+                        FileAction::Keep => {}
+                        FileAction::Add => println!("  add {fn_}"),
+                        FileAction::Replace => println!("  replace {fn_}"),
+                        FileAction::Overwrite => println!("  overwrite untracked {fn_}"),
+                        FileAction::Conflict => println!("  conflict (untracked) {fn_}"),
+                        FileAction::New => {
+                            println!("  keep changed {fn_}, new as {fn_}.atxpkg_new")
+                        }
+                        FileAction::Save => println!("  save changed {fn_} as {fn_}.atxpkg_save"),
+                        FileAction::Remove => println!("  remove {fn_}"),
+                        FileAction::Missing => println!("  missing {fn_}"),
+                    }
+                }
             }
         }
         Command::Check(args) => {
-            let installed_packages = get_installed_packages(&db_fn)?;
-            let packages = if args.packages.is_empty() {
-                installed_packages
-                    .keys()
-                    .map(|x| x.to_string())
-                    .sorted()
-                    .collect()
-            } else {
-                args.packages.to_vec()
-            };
-            check_packages(packages, &installed_packages, &mainargs.prefix)?;
+            atxpkg.check(args.packages.to_vec())?;
         }
         Command::ListAvailable(args) => {
-            for (package_name, package_ver) in list_available(
-                args.packages.to_vec(),
-                repos,
-                args.offline,
-                args.unverified_ssl,
-            )? {
-                if package_ver.is_empty() {
-                    println!("{package_name}");
-                } else {
-                    println!("{package_name}-{package_ver}");
+            let available = atxpkg.list_available(args.packages.to_vec())?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&available)?);
+                return Ok(0);
+            }
+            for package in available {
+                match package.version {
+                    Some(version) => println!("{}-{version}", package.name),
+                    None => println!("{}", package.name),
                 }
             }
         }
         Command::ListInstalled => {
-            let installed_packages = get_installed_packages(&db_fn)?;
-            for (package_name, package_info) in
-                installed_packages.iter().sorted_unstable_by_key(|x| x.0)
-            {
-                println!("{package_name}-{}", package_info.version);
+            let installed = atxpkg.list_installed()?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&installed)?);
+                return Ok(0);
+            }
+            for package in installed {
+                let mut tags = vec![];
+                match &package.hold {
+                    Some(Hold {
+                        max_version: Some(max_version),
+                    }) => tags.push(format!("held at <={max_version}")),
+                    Some(Hold { max_version: None }) => tags.push("held".to_string()),
+                    None => {}
+                }
+                if let Some(package_channel) = package.channel {
+                    tags.push(format!("channel {package_channel}"));
+                }
+                match tags.is_empty() {
+                    true => println!("{}-{}", package.name, package.version),
+                    false => println!("{}-{} [{}]", package.name, package.version, tags.join(", ")),
+                }
+            }
+        }
+        Command::Rollback(args) => {
+            if atxpkg.rollback(args.packages.to_vec())? {
+                log::info!("rollback completed");
+                status!("rollback completed");
+            }
+        }
+        Command::Export(args) => {
+            let manifest = atxpkg.export(&args.manifest)?;
+            status!(
+                "{} packages exported to {}",
+                manifest.packages.len(),
+                args.manifest
+            );
+        }
+        Command::Sync(args) => {
+            let manifest = get_manifest(&args.manifest)?;
+            if atxpkg.sync(&manifest, args.remove)? {
+                log::info!("sync completed");
+                status!("sync completed");
+            }
+        }
+        Command::History(args) => {
+            let history = atxpkg.history()?;
+            let Some(id) = args.id else {
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&history)?);
+                    return Ok(0);
+                }
+                for entry in &history {
+                    let outcome = match entry.error {
+                        Some(_) => "failed",
+                        None => "ok",
+                    };
+                    println!(
+                        "{}  {}  {}  {outcome}  {}",
+                        entry.id,
+                        format_timestamp(entry.t),
+                        entry.user,
+                        entry.command
+                    );
+                }
+                return Ok(0);
+            };
+            let Some(entry) = history.iter().find(|x| x.id == id) else {
+                anyhow::bail!("transaction {id} not found in history");
+            };
+            if json {
+                println!("{}", serde_json::to_string_pretty(entry)?);
+                return Ok(0);
+            }
+            println!("id: {}", entry.id);
+            println!("time: {}", format_timestamp(entry.t));
+            println!("user: {}", entry.user);
+            println!("command: {}", entry.command);
+            match &entry.error {
+                Some(err) => println!("outcome: failed ({err})"),
+                None => println!("outcome: ok"),
+            }
+            println!("changes:");
+            for change in &entry.changes {
+                println!("  {change}");
+            }
+            println!("saved files:");
+            for saved_file in &entry.saved_files {
+                println!("  {saved_file}");
+            }
+        }
+        Command::Undo(args) => {
+            if atxpkg.undo(args.id)? {
+                log::info!("undo completed");
+                status!("undo completed");
+            }
+        }
+        Command::Hold(args) => {
+            atxpkg.hold(args.packages.to_vec(), args.max_version.clone())?;
+            for p in &args.packages {
+                status!("{p} held");
+            }
+        }
+        Command::SwitchChannel(args) => {
+            let channel = args.channel.parse::<Channel>()?;
+            let res = atxpkg.switch_channel(channel, args.packages.to_vec())?;
+            if args.packages.is_empty() {
+                status!("channel set to {channel}, packages will follow it on update");
+            } else if res {
+                log::info!("switch channel completed");
+                status!("switch channel completed");
+            }
+        }
+        Command::Unhold(args) => {
+            atxpkg.unhold(args.packages.to_vec())?;
+            for p in &args.packages {
+                status!("{p} unheld");
             }
         }
         Command::ShowUntracked(args) => {
-            let installed_packages = get_installed_packages(&db_fn)?;
-            let untracked =
-                get_untracked(args.paths.clone(), &installed_packages, &mainargs.prefix)?;
+            let untracked = atxpkg.untracked(args.paths.clone())?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&untracked)?);
+                return Ok(0);
+            }
             for x in untracked {
                 println!("unknown: {x}");
             }
         }
+        Command::Owns(args) => {
+            let owners = atxpkg.owners(args.paths.clone())?;
+            let ret = u8::from(owners.iter().any(|x| x.owner.is_none()));
+            if json {
+                println!("{}", serde_json::to_string_pretty(&owners)?);
+                return Ok(ret);
+            }
+            for OwnedPath { path, owner } in owners {
+                let Some(owner) = owner else {
+                    println!("untracked: {path}");
+                    continue;
+                };
+                let mut flags = vec![];
+                if owner.is_dir {
+                    flags.push("dir");
+                }
+                if owner.is_backup {
+                    flags.push("backup");
+                }
+                if owner.is_modified {
+                    flags.push("modified");
+                }
+                if owner.is_missing {
+                    flags.push("missing");
+                }
+                if flags.is_empty() {
+                    println!("{path}: {}-{}", owner.package_name, owner.version);
+                } else {
+                    println!(
+                        "{path}: {}-{} ({})",
+                        owner.package_name,
+                        owner.version,
+                        flags.join(", ")
+                    );
+                }
+            }
+            return Ok(ret);
+        }
+        Command::Info(args) => {
+            let details = atxpkg.package_details(&args.package)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&details)?);
+                return Ok(0);
+            }
+            println!("name: {}", details.name);
+            println!("version: {}", details.version);
+            match (details.installed, details.t) {
+                (true, Some(t)) => println!("installed: {}", format_timestamp(t)),
+                (true, None) => println!("installed: yes"),
+                (false, _) => println!("installed: no"),
+            }
+            println!("files: {}", details.files);
+            println!("dirs: {}", details.dirs);
+            println!("size: {}", indicatif::HumanBytes(details.size));
+            println!("backup: {}", details.backup.join(", "));
+            println!("repo: {}", details.repo.unwrap_or_default());
+            println!("newer versions: {}", details.newer_versions.join(", "));
+        }
+        Command::Files(args) => {
+            let files = atxpkg.package_files(&args.package)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&files)?);
+            } else {
+                for f in files {
+                    match (f.is_dir, f.is_backup) {
+                        (true, _) => println!("{}/", f.path),
+                        (false, true) => println!("{} [backup]", f.path),
+                        (false, false) => println!("{}", f.path),
+                    }
+                }
+            }
+        }
+        Command::Recover => {
+            if !atxpkg.recovered() {
+                status!("nothing to recover");
+            }
+        }
         Command::CleanCache => {
-            clean_cache(&cache_dir)?;
+            clean_cache(atxpkg.cache_dir())?;
         }
     }
 
     Ok(0)
 }
 
+/// Exit code for `err`, stable and documented in README (2 is used by clap for usage errors).
+fn get_exit_code(err: &anyhow::Error) -> u8 {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<Error>() {
+            return match err {
+                Error::NotInstalled(_) => 3,
+                Error::NotAvailable(_) => 4,
+                Error::Conflict(_) => 5,
+                Error::Network(_) => 6,
+                Error::Integrity(_) => 7,
+                Error::Declined => 8,
+                Error::Locked(_) => 9,
+                Error::Interrupted(_) => 10,
+            };
+        }
+        if cause.is::<reqwest::Error>() {
+            return 6;
+        }
+    }
+    1
+}
+
 fn main() -> ExitCode {
-    //ExitCode::from(main_sub().unwrap())
-    let res = main_sub();
-    if let Err(err) = &res {
-        eprintln!("{err}");
-        //eprintln!("{}", err.backtrace());
+    match main_sub() {
+        Ok(ret) => ExitCode::from(ret),
+        Err(err) => {
+            eprintln!("{err}");
+            //eprintln!("{}", err.backtrace());
+            ExitCode::from(get_exit_code(&err))
+        }
     }
-    ExitCode::from(res.unwrap())
 }
@@ -1,14 +1,218 @@
 use itertools::Itertools;
 use md5::{Digest, Md5};
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufReader, BufWriter, IsTerminal, Read, Write};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::Path;
-use std::time::{Duration, UNIX_EPOCH};
+use std::sync::{Arc, RwLock};
+use std::time::UNIX_EPOCH;
 
 const MAX_CONCURRENT_DOWNLOADS: u32 = 2;
+const MAX_CONCURRENT_CHECKS: u32 = 4;
 const MD5_BUFFER_SIZE: usize = 1024 * 1024; // 1MB buffer for MD5 calculation
+const DB_BACKUP_COUNT: usize = 5; // previous generations of installed db kept as .1 (newest) to .5
+
+static REPORTER: RwLock<Option<Arc<dyn Reporter>>> = RwLock::new(None);
+
+thread_local! {
+    /// `Atxpkg` session whose operation runs on this thread, see `in_session`.
+    static SESSION: RefCell<Option<SessionScope>> = const { RefCell::new(None) };
+}
+
+/// What operations of an `Atxpkg` session take from it instead of the process-wide defaults.
+#[derive(Clone)]
+struct SessionScope {
+    reporter: Arc<dyn Reporter>,
+    /// Command recorded in history.
+    command: String,
+}
+
+/// Sets the reporter receiving progress and messages of the free functions and answering their
+/// confirmations, `SilentReporter` is used until set. Operations of `Atxpkg` use the reporter of
+/// their session instead (see `AtxpkgBuilder::reporter`).
+pub fn set_reporter(reporter: Arc<dyn Reporter>) {
+    *REPORTER.write().unwrap() = Some(reporter);
+}
+
+/// Reporter of the running `Atxpkg` operation, the one set by `set_reporter` otherwise.
+pub fn reporter() -> Arc<dyn Reporter> {
+    if let Some(session) = SESSION.with_borrow(Option::clone) {
+        return session.reporter;
+    }
+    match &*REPORTER.read().unwrap() {
+        Some(reporter) => reporter.clone(),
+        None => Arc::new(SilentReporter),
+    }
+}
+
+/// Command of the running `Atxpkg` operation, the command line of the process otherwise.
+fn command() -> String {
+    match SESSION.with_borrow(Option::clone) {
+        Some(session) => session.command,
+        None => std::env::args().join(" "),
+    }
+}
+
+/// Runs `f` with `reporter()` and `command()` returning those of `session` on this thread (the
+/// parallel downloads and checks report through progress groups created on the calling thread).
+fn in_session<T>(session: &SessionScope, f: impl FnOnce() -> T) -> T {
+    struct Restore(Option<SessionScope>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            SESSION.set(self.0.take());
+        }
+    }
+
+    let _restore = Restore(SESSION.replace(Some(session.clone())));
+    f()
+}
+
+/// What a progress counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressUnit {
+    Bytes,
+    /// Files, directories, archive entries...
+    Items,
+}
+
+/// Progress of a single task (download of a package, install of its files...). Length 0 means
+/// it's not known (yet).
+pub trait Progress: Send + Sync {
+    fn set_length(&self, _len: u64) {}
+    fn set_position(&self, _pos: u64) {}
+    fn inc(&self, _delta: u64) {}
+    /// Message about the task (file saved aside, missing file...) printed while it's running.
+    fn println(&self, _msg: &str) {}
+    fn finish(&self) {}
+}
+
+/// Progresses of tasks running in parallel (downloads, checks).
+pub trait ProgressGroup: Send + Sync {
+    fn progress(
+        &self,
+        prefix: &str,
+        action: &str,
+        unit: ProgressUnit,
+        len: u64,
+    ) -> Box<dyn Progress>;
+    fn finish(&self) {}
+}
+
+/// Receives progress and messages of operations and answers their confirmations. All methods
+/// have silent defaults, confirmations are declined (use the `yes` option when unattended).
+pub trait Reporter: Send + Sync {
+    /// Starts progress of a task, `prefix` is its subject (package, repo, path) and `action` what's
+    /// being done (`install`, `check`...) if not obvious.
+    fn progress(
+        &self,
+        _prefix: &str,
+        _action: &str,
+        _unit: ProgressUnit,
+        _len: u64,
+    ) -> Box<dyn Progress> {
+        Box::new(SilentProgress)
+    }
+
+    fn progress_group(&self) -> Box<dyn ProgressGroup> {
+        Box::new(SilentProgress)
+    }
+
+    /// Progress message (`xyz-1.0-1 is now installed`...).
+    fn message(&self, _msg: &str) {}
+
+    /// Problem not stopping the operation (unreadable repository, file failed to restore...).
+    fn warning(&self, _msg: &str) {}
+
+    /// Changes about to be made, reported before the confirmation.
+    fn plan(&self, _plan: &[PlannedChange]) {}
+
+    /// Result of `check_packages`.
+    fn check_report(&self, _report: &CheckReport) {}
+
+    fn confirm(&self, _prompt: &str, _default: bool) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Reporter ignoring everything and declining confirmations.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SilentReporter;
+
+impl Reporter for SilentReporter {}
+
+struct SilentProgress;
+
+impl Progress for SilentProgress {}
+
+impl ProgressGroup for SilentProgress {
+    fn progress(&self, _: &str, _: &str, _: ProgressUnit, _: u64) -> Box<dyn Progress> {
+        Box::new(SilentProgress)
+    }
+}
+
+/// Advances `progress` by the bytes read.
+struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a dyn Progress,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.inc(n as u64);
+        Ok(n)
+    }
+}
+
+/// Failures callers may want to tell apart, the CLI maps them to exit codes. Other failures are
+/// plain `anyhow` errors, the typed ones can be found with `anyhow::Error::downcast_ref`.
+#[derive(Debug)]
+pub enum Error {
+    /// Package (or the requested version of it) is not installed.
+    NotInstalled(String),
+    /// Package (or a version matching the spec) is not available in the repositories.
+    NotAvailable(String),
+    /// Package already installed, untracked file in the way or package changed meanwhile.
+    Conflict(String),
+    /// Repository or download failure.
+    Network(String),
+    /// Installed files don't match the package or package file doesn't match the database.
+    Integrity(String),
+    /// Confirmation answered no.
+    Declined,
+    /// Root dir locked by another atxpkg.
+    Locked(String),
+    /// Interrupted operation has to be (or could not be) recovered.
+    Interrupted(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NotInstalled(msg)
+            | Error::NotAvailable(msg)
+            | Error::Conflict(msg)
+            | Error::Network(msg)
+            | Error::Integrity(msg)
+            | Error::Locked(msg)
+            | Error::Interrupted(msg) => write!(f, "{msg}"),
+            Error::Declined => write!(f, "aborted"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// `println!` for progress messages, they go to the `Reporter`.
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::reporter().message(&format!($($arg)*))
+    };
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct InstalledPackage {
@@ -16,6 +220,304 @@ pub struct InstalledPackage {
     pub version: String,
     pub md5sums: HashMap<String, Option<String>>,
     pub backup: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold: Option<Hold>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<Channel>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<PreviousPackage>,
+    /// Size and modification time of installed files, lets `check` skip hashing of unchanged files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_stats: Option<HashMap<String, FileStat>>,
+}
+
+/// Stat data of installed file (or dir) recorded at install/update time.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FileStat {
+    pub size: u64,
+    pub mtime: f64,
+    /// Unix permission bits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+}
+
+impl FileStat {
+    fn new(file_path: &str) -> anyhow::Result<Self> {
+        Self::from_metadata(&std::fs::metadata(file_path)?)
+    }
+
+    fn from_metadata(metadata: &std::fs::Metadata) -> anyhow::Result<Self> {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        Ok(Self {
+            size: metadata.len(),
+            mtime: metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)?
+                .as_secs_f64(),
+            mode,
+        })
+    }
+}
+
+/// Desired set of installed packages, see `export_manifest` and `sync_packages`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Manifest {
+    pub packages: std::collections::BTreeMap<String, ManifestPackage>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ManifestPackage {
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold: Option<Hold>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<Channel>,
+}
+
+/// Transaction recorded in history.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub t: f64,
+    pub user: String,
+    pub command: String,
+    pub changes: Vec<PackageChange>,
+    /// Files saved aside (`.atxpkg_new`, `.atxpkg_save`, `.atxpkg_backup`).
+    pub saved_files: Vec<String>,
+    /// Error of failed (and rolled back) transaction.
+    pub error: Option<String>,
+}
+
+/// Change of installed package, `None` version stands for not installed.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PackageChange {
+    pub name: String,
+    pub version_old: Option<String>,
+    pub version_new: Option<String>,
+}
+
+impl std::fmt::Display for PackageChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = &self.name;
+        match (&self.version_old, &self.version_new) {
+            (None, Some(new)) => write!(f, "install {name}-{new}"),
+            (Some(old), None) => write!(f, "remove {name}-{old}"),
+            (Some(old), Some(new)) => write!(f, "update {name}-{old} to {name}-{new}"),
+            (None, None) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Package (version) replaced by the last update, its package file is kept in `rollback` subdir of
+/// cache if it was at hand.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PreviousPackage {
+    pub name: String,
+    pub version: String,
+}
+
+/// Held package is not updated unless explicitly requested (or only up to `max_version`).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Hold {
+    pub max_version: Option<String>,
+}
+
+/// Release channel of a package, `stable` packages are published as `name`, `dev` ones as `name.dev`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Stable,
+    Dev,
+}
+
+impl Channel {
+    /// Returns name of the package in this channel.
+    pub fn package_name(&self, package_name: &str) -> String {
+        let base_name = package_name.strip_suffix(".dev").unwrap_or(package_name);
+        match self {
+            Channel::Stable => base_name.to_string(),
+            Channel::Dev => format!("{base_name}.dev"),
+        }
+    }
+}
+
+impl std::str::FromStr for Channel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stable" => Ok(Channel::Stable),
+            "dev" => Ok(Channel::Dev),
+            _ => anyhow::bail!("invalid channel: {s} (expected stable or dev)"),
+        }
+    }
+}
+
+impl std::fmt::Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Channel::Stable => write!(f, "stable"),
+            Channel::Dev => write!(f, "dev"),
+        }
+    }
+}
+
+/// Package as listed by `list_available`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct AvailablePackage {
+    pub name: String,
+    /// `None` when listing all package names.
+    pub version: Option<String>,
+}
+
+/// Installed package as listed by `list_installed`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ListedPackage {
+    pub name: String,
+    pub version: String,
+    pub hold: Option<Hold>,
+    /// Channel followed by the package (its own or the global one).
+    pub channel: Option<Channel>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PackageDetails {
+    pub name: String,
+    pub version: String,
+    pub installed: bool,
+    pub t: Option<f64>,
+    pub files: usize,
+    pub dirs: usize,
+    pub size: u64,
+    pub backup: Vec<String>,
+    pub repo: Option<String>,
+    pub newer_versions: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PackageFile {
+    pub path: String,
+    pub is_dir: bool,
+    pub is_backup: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileAction {
+    Add,
+    Replace,
+    Keep,
+    Overwrite,
+    Conflict,
+    New,
+    Save,
+    Remove,
+    Missing,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct UpdatePreview {
+    pub name_old: String,
+    pub version_old: String,
+    pub name_new: String,
+    pub version_new: String,
+    pub files: Vec<(String, FileAction)>,
+}
+
+/// Package operation of a plan that is confirmed before install/update/remove.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlannedChange {
+    pub action: PlanAction,
+    pub name: String,
+    pub version: String,
+    /// Package replaced by an update.
+    pub name_old: Option<String>,
+    pub version_old: Option<String>,
+    /// Max version for `hold`, channel for `set_channel` (`None` to follow the global one).
+    pub detail: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanAction {
+    Install,
+    Reinstall,
+    Update,
+    Downgrade,
+    Download,
+    Remove,
+    Hold,
+    Unhold,
+    SetChannel,
+}
+
+impl std::fmt::Display for PlannedChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self.action {
+            PlanAction::Install => "install",
+            PlanAction::Reinstall => "reinstall",
+            PlanAction::Update => "update",
+            PlanAction::Downgrade => "downgrade",
+            PlanAction::Download => "download",
+            PlanAction::Remove => "remove",
+            PlanAction::Hold => "hold",
+            PlanAction::Unhold => "unhold",
+            PlanAction::SetChannel => "set channel of",
+        };
+        match (&self.name_old, &self.version_old, &self.detail) {
+            (Some(name_old), Some(version_old), _) => write!(
+                f,
+                "{action} {name_old}-{version_old} -> {}-{}",
+                self.name, self.version
+            ),
+            (_, _, detail) if self.action == PlanAction::SetChannel => write!(
+                f,
+                "{action} {}-{} to {}",
+                self.name,
+                self.version,
+                detail.as_deref().unwrap_or("global")
+            ),
+            (_, _, Some(max_version)) => write!(
+                f,
+                "{action} {}-{} at <={max_version}",
+                self.name, self.version
+            ),
+            _ => write!(f, "{action} {}-{}", self.name, self.version),
+        }
+    }
+}
+
+struct PackageListing {
+    dirs: Vec<String>,
+    files: Vec<(String, u64)>,
+    backup: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PathOwner {
+    pub package_name: String,
+    pub version: String,
+    pub is_dir: bool,
+    pub is_backup: bool,
+    pub is_modified: bool,
+    pub is_missing: bool,
+}
+
+/// Path as resolved by `get_owners`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OwnedPath {
+    /// Path relative to the prefix.
+    pub path: String,
+    /// `None` when the path is untracked.
+    pub owner: Option<PathOwner>,
 }
 
 #[derive(Clone)]
@@ -26,6 +528,10 @@ struct PackageUpdate {
     version_new: String,
     url: String,
     local_fn: String,
+    /// Origin repo of `url`, see `get_origin_repo`.
+    repo: Option<String>,
+    url_old: Option<String>,
+    local_fn_old: Option<String>,
 }
 
 fn as_unix_path(pth: &Path) -> String {
@@ -36,7 +542,7 @@ fn as_unix_path(pth: &Path) -> String {
     // TODO: this used to fuck up things for windows - investigate and fix
     #[cfg(target_os = "linux")]
     if pth.is_absolute() {
-        return format!("/{ret}");
+        return format!("/{}", ret.trim_start_matches('/'));
     }
     ret
 }
@@ -57,19 +563,726 @@ fn move_file(from: &str, to: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn make_progress_bar(
-    len: u64,
-    prefix: &str,
-    template: &str,
-) -> anyhow::Result<indicatif::ProgressBar> {
-    let progress_bar = indicatif::ProgressBar::new(len).with_prefix(prefix.to_string());
-    progress_bar.set_style(
-        indicatif::ProgressStyle::default_bar()
-            .template(template)?
-            .tick_chars(r"|/-\ ")
-            .progress_chars("##-"),
-    );
-    Ok(progress_bar)
+const JOURNAL_FN: &str = "journal.jsonl";
+
+/// Filesystem changes done while installing/updating/removing a set of packages. Replaced and
+/// deleted files are stashed aside until the whole set succeeds so that the previous state can be
+/// restored. Every change is written to the journal before it is done so that an operation
+/// interrupted by a crash can be reverted by `recover`.
+struct Transaction {
+    dir: tempfile::TempDir,
+    journal: File,
+    undo_log: Vec<Undo>,
+    /// Side effects outside of the prefix, done only once the transaction is committed.
+    on_commit: Vec<Box<dyn FnOnce()>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Undo {
+    CreatedFile(String),
+    CreatedDir(String),
+    StashedFile {
+        path: String,
+        stash_fn: String,
+    },
+    RemovedDir {
+        path: String,
+        permissions: FilePermissions,
+    },
+    DirAttrs {
+        path: String,
+        permissions: FilePermissions,
+        mod_time: std::time::SystemTime,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JournalEntry {
+    Begin {
+        t: f64,
+        installed_packages: Option<HashMap<String, InstalledPackage>>,
+    },
+    Undo(Undo),
+    Commit {
+        installed_packages: HashMap<String, InstalledPackage>,
+    },
+}
+
+/// Serializable counterpart of `std::fs::Permissions`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct FilePermissions {
+    readonly: bool,
+    mode: Option<u32>,
+}
+
+impl FilePermissions {
+    fn new(permissions: &std::fs::Permissions) -> Self {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(permissions.mode())
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        FilePermissions {
+            readonly: permissions.readonly(),
+            mode,
+        }
+    }
+
+    fn apply(&self, path: &str) -> anyhow::Result<()> {
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            permissions.set_mode(mode);
+        }
+        #[cfg(not(unix))]
+        permissions.set_readonly(self.readonly);
+        std::fs::set_permissions(path, permissions)?;
+        Ok(())
+    }
+}
+
+impl Undo {
+    fn path(&self) -> &str {
+        match self {
+            Undo::CreatedFile(path)
+            | Undo::CreatedDir(path)
+            | Undo::StashedFile { path, .. }
+            | Undo::RemovedDir { path, .. }
+            | Undo::DirAttrs { path, .. } => path,
+        }
+    }
+
+    /// Reverts the change. The change is journaled before it's done so it may not have happened
+    /// (or only partially) when recovering from a crash.
+    fn revert(&self) -> anyhow::Result<()> {
+        match self {
+            Undo::CreatedFile(path) => try_delete(path)?,
+            Undo::CreatedDir(path) => {
+                if Path::new(path).is_dir() {
+                    std::fs::remove_dir(path)?;
+                }
+            }
+            Undo::StashedFile { path, stash_fn } => {
+                if Path::new(stash_fn).exists() {
+                    move_file(stash_fn, path)?;
+                }
+            }
+            Undo::RemovedDir { path, permissions } => {
+                if !Path::new(path).exists() {
+                    std::fs::create_dir(path)?;
+                }
+                permissions.apply(path)?;
+            }
+            Undo::DirAttrs {
+                path,
+                permissions,
+                mod_time,
+            } => {
+                if Path::new(path).exists() {
+                    permissions.apply(path)?;
+                    filetime::set_file_times(path, (*mod_time).into(), (*mod_time).into())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transaction {
+    /// Starts new transaction, `installed_packages` are journaled so that the database can be
+    /// restored by `recover` as well.
+    fn new(
+        tmp_dir_prefix: &str,
+        installed_packages: Option<&HashMap<String, InstalledPackage>>,
+    ) -> anyhow::Result<Self> {
+        // committed journals are left alone, the caller might not have saved their database yet
+        for journal_dir in find_journals(tmp_dir_prefix)? {
+            if read_journal(&journal_dir)?.commit.is_none() {
+                anyhow::bail!(Error::Interrupted(format!(
+                    "interrupted operation found in {}, run `atxpkg recover` first",
+                    as_unix_path(&journal_dir)
+                )));
+            }
+        }
+
+        let dir = tempfile::Builder::new()
+            .prefix("transaction")
+            .tempdir_in(tmp_dir_prefix)?;
+        let journal = File::create(dir.path().join(JOURNAL_FN))?;
+        let mut transaction = Transaction {
+            dir,
+            journal,
+            undo_log: vec![],
+            on_commit: vec![],
+        };
+        transaction.write_journal(&JournalEntry::Begin {
+            t: UNIX_EPOCH.elapsed()?.as_secs_f64(),
+            installed_packages: installed_packages.cloned(),
+        })?;
+        Ok(transaction)
+    }
+
+    /// Appends entry to the journal and makes sure it is on the disk before going on.
+    fn write_journal(&mut self, entry: &JournalEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.journal.write_all(line.as_bytes())?;
+        self.journal.sync_data()?;
+        Ok(())
+    }
+
+    fn log(&mut self, undo: Undo) -> anyhow::Result<()> {
+        self.write_journal(&JournalEntry::Undo(undo.clone()))?;
+        self.undo_log.push(undo);
+        Ok(())
+    }
+
+    /// Moves existing file out of the way (or remembers that the path is to be created).
+    fn stash(&mut self, path: &str) -> anyhow::Result<()> {
+        if std::fs::symlink_metadata(path).is_err() {
+            return self.log(Undo::CreatedFile(path.to_string()));
+        }
+        if !Path::new(path).is_file() {
+            anyhow::bail!("not a file: {path}");
+        }
+        let stash_fn = format!("{}/{}", as_unix_path(self.dir.path()), self.undo_log.len());
+        self.log(Undo::StashedFile {
+            path: path.to_string(),
+            stash_fn: stash_fn.clone(),
+        })?;
+        log::trace!("stashing {path} as {stash_fn}");
+        if std::fs::rename(path, &stash_fn).is_err() {
+            // probably different filesystem, the stashed file must never be seen incomplete
+            let part_fn = format!("{stash_fn}.part");
+            std::fs::copy(path, &part_fn)?;
+            std::fs::rename(&part_fn, &stash_fn)?;
+            try_delete(path)?;
+        }
+        Ok(())
+    }
+
+    fn put_file(&mut self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.stash(to)?;
+        move_file(from, to)
+    }
+
+    fn move_file(&mut self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.stash(to)?;
+        std::fs::copy(from, to)?;
+        self.delete(from)
+    }
+
+    fn delete(&mut self, path: &str) -> anyhow::Result<()> {
+        if std::fs::symlink_metadata(path).is_err() {
+            return Ok(());
+        }
+        self.stash(path)
+    }
+
+    fn create_dir(&mut self, path: &str) -> anyhow::Result<()> {
+        if Path::new(path).exists() {
+            return Ok(());
+        }
+        self.log(Undo::CreatedDir(path.to_string()))?;
+        std::fs::create_dir(path)?;
+        Ok(())
+    }
+
+    fn remove_dir(&mut self, path: &str) -> anyhow::Result<()> {
+        let permissions = FilePermissions::new(&std::fs::metadata(path)?.permissions());
+        self.log(Undo::RemovedDir {
+            path: path.to_string(),
+            permissions,
+        })?;
+        std::fs::remove_dir(path)?;
+        Ok(())
+    }
+
+    fn set_dir_attrs(
+        &mut self,
+        path: &str,
+        permissions: std::fs::Permissions,
+        mod_time: std::time::SystemTime,
+    ) -> anyhow::Result<()> {
+        let metadata = std::fs::metadata(path)?;
+        self.log(Undo::DirAttrs {
+            path: path.to_string(),
+            permissions: FilePermissions::new(&metadata.permissions()),
+            mod_time: metadata.modified().unwrap_or(mod_time),
+        })?;
+        std::fs::set_permissions(path, permissions)?;
+        filetime::set_file_times(path, mod_time.into(), mod_time.into())?;
+        Ok(())
+    }
+
+    /// Files saved aside (as `.atxpkg_new`, `.atxpkg_save` or `.atxpkg_backup`) so far.
+    fn saved_files(&self) -> Vec<String> {
+        self.undo_log
+            .iter()
+            .filter_map(|undo| match undo {
+                Undo::CreatedFile(path) | Undo::StashedFile { path, .. }
+                    if [".atxpkg_new", ".atxpkg_save", ".atxpkg_backup"]
+                        .iter()
+                        .any(|x| path.ends_with(x)) =>
+                {
+                    Some(path.clone())
+                }
+                _ => None,
+            })
+            .unique()
+            .collect()
+    }
+
+    /// Drops the stashed files. With `installed_packages` the journal is kept (marked as
+    /// committed) so that `recover` can update the database should it not get saved, it's removed
+    /// by `remove_finished_journals` once the database is saved.
+    fn commit(
+        mut self,
+        installed_packages: Option<&HashMap<String, InstalledPackage>>,
+    ) -> anyhow::Result<()> {
+        log::debug!("committing transaction ({} changes)", self.undo_log.len());
+        let on_commit = std::mem::take(&mut self.on_commit);
+        let Some(installed_packages) = installed_packages else {
+            self.dir.close()?;
+            on_commit.into_iter().for_each(|f| f());
+            return Ok(());
+        };
+        self.write_journal(&JournalEntry::Commit {
+            installed_packages: installed_packages.clone(),
+        })?;
+        for undo in &self.undo_log {
+            if let Undo::StashedFile { stash_fn, .. } = undo
+                && let Err(err) = std::fs::remove_file(stash_fn)
+            {
+                log::warn!("failed to remove {stash_fn}: {err}");
+            }
+        }
+        let _ = self.dir.keep();
+        on_commit.into_iter().for_each(|f| f());
+        Ok(())
+    }
+
+    /// Schedules `f` to run after successful commit.
+    fn on_commit(&mut self, f: impl FnOnce() + 'static) {
+        self.on_commit.push(Box::new(f));
+    }
+
+    /// Undoes all changes in reverse order, continues on errors so that as much as possible is
+    /// restored. On failure the journal is kept for `recover` to retry.
+    fn rollback(self) -> anyhow::Result<()> {
+        log::info!("rolling back transaction ({} changes)", self.undo_log.len());
+        let err_count = revert_changes(&self.undo_log);
+        if err_count > 0 {
+            let dir = self.dir.keep();
+            anyhow::bail!(
+                "failed to restore {err_count} paths, stashed files kept in {}",
+                as_unix_path(&dir)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Reverts changes in reverse order, returns the number of paths that failed to restore.
+fn revert_changes(undo_log: &[Undo]) -> usize {
+    let mut err_count = 0;
+    for undo in undo_log.iter().rev() {
+        if let Err(err) = undo.revert() {
+            let path = undo.path();
+            log::error!("failed to restore {path}: {err}");
+            reporter().warning(&format!("failed to restore {path}: {err}"));
+            err_count += 1;
+        }
+    }
+    err_count
+}
+
+/// Runs `f` in a transaction, on error the files and `installed_packages` are restored to the
+/// previous state. The outcome is recorded in history if `history_fn` is given.
+fn with_transaction<T>(
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+    tmp_dir_prefix: &str,
+    history_fn: Option<&str>,
+    f: impl FnOnce(&mut HashMap<String, InstalledPackage>, &mut Transaction) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let t = UNIX_EPOCH.elapsed()?.as_secs_f64();
+    let installed_packages_orig = installed_packages.clone();
+    let mut transaction = Transaction::new(tmp_dir_prefix, Some(installed_packages))?;
+    let res = f(installed_packages, &mut transaction);
+    // on failure these are the changes that got rolled back
+    let changes = get_package_changes(&installed_packages_orig, installed_packages);
+    // holds and channels (e.g. set by `sync`) aren't package changes but are worth recording
+    let attrs_changed = installed_packages_orig.iter().any(|(name, before)| {
+        installed_packages
+            .get(name)
+            .is_some_and(|after| after.hold != before.hold || after.channel != before.channel)
+    });
+    let saved_files = transaction.saved_files();
+    let res = match res {
+        Ok(x) => transaction.commit(Some(installed_packages)).map(|_| x),
+        Err(err) => {
+            *installed_packages = installed_packages_orig;
+            if !transaction.undo_log.is_empty() {
+                reporter().warning(&format!("{err}, rolling back"));
+                match transaction.rollback() {
+                    Ok(()) => {
+                        reporter().warning("rollback successful");
+                        Err(err)
+                    }
+                    Err(rollback_err) => {
+                        Err(err.context(format!("rollback failed: {rollback_err}")))
+                    }
+                }
+            } else {
+                Err(err)
+            }
+        }
+    };
+
+    let declined = res
+        .as_ref()
+        .is_err_and(|err| matches!(err.downcast_ref::<Error>(), Some(Error::Declined)));
+    if let Some(history_fn) = history_fn
+        && (!changes.is_empty() || attrs_changed || (res.is_err() && !declined))
+    {
+        let entry = HistoryEntry {
+            id: 0,
+            t,
+            user: get_user(),
+            command: command(),
+            changes,
+            saved_files: match res {
+                Ok(_) => saved_files,
+                Err(_) => vec![],
+            },
+            error: res.as_ref().err().map(|err| format!("{err:#}")),
+        };
+        if let Err(err) = add_history_entry(history_fn, entry) {
+            log::error!("failed to record history: {err}");
+            reporter().warning(&format!("failed to record history: {err}"));
+        }
+    }
+
+    res
+}
+
+/// Contents of a transaction journal.
+struct Journal {
+    t: f64,
+    installed_packages: Option<HashMap<String, InstalledPackage>>,
+    undo_log: Vec<Undo>,
+    commit: Option<HashMap<String, InstalledPackage>>,
+}
+
+impl Journal {
+    /// Whether the operation still has to be finished by `recover`: it either hasn't been
+    /// committed or `installed_packages` (the saved database) are still in the state from before it.
+    fn is_unfinished(&self, installed_packages: &HashMap<String, InstalledPackage>) -> bool {
+        match &self.commit {
+            None => true,
+            Some(committed_packages) => {
+                self.installed_packages.as_ref() == Some(installed_packages)
+                    && installed_packages != committed_packages
+            }
+        }
+    }
+}
+
+fn find_journals(tmp_dir_prefix: &str) -> anyhow::Result<Vec<std::path::PathBuf>> {
+    let mut ret = vec![];
+    if !Path::new(tmp_dir_prefix).exists() {
+        return Ok(ret);
+    }
+    for entry in std::fs::read_dir(tmp_dir_prefix)? {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .is_some_and(|x| x.to_string_lossy().starts_with("transaction"))
+            && path.join(JOURNAL_FN).exists()
+        {
+            ret.push(path);
+        }
+    }
+    Ok(ret)
+}
+
+fn read_journal(journal_dir: &Path) -> anyhow::Result<Journal> {
+    let mut journal = Journal {
+        t: 0.0,
+        installed_packages: None,
+        undo_log: vec![],
+        commit: None,
+    };
+    let content = std::fs::read_to_string(journal_dir.join(JOURNAL_FN))?;
+    let lines = content.lines().collect::<Vec<_>>();
+    for (i, line) in lines.iter().enumerate() {
+        let entry = match serde_json::from_str::<JournalEntry>(line) {
+            Ok(entry) => entry,
+            // torn write of the last entry, the change itself hasn't been done
+            Err(err) if i == lines.len() - 1 => {
+                log::warn!("ignoring incomplete journal entry: {err}");
+                break;
+            }
+            Err(err) => anyhow::bail!("corrupted journal {}: {err}", as_unix_path(journal_dir)),
+        };
+        match entry {
+            JournalEntry::Begin {
+                t,
+                installed_packages,
+            } => {
+                journal.t = t;
+                journal.installed_packages = installed_packages;
+            }
+            JournalEntry::Undo(undo) => journal.undo_log.push(undo),
+            JournalEntry::Commit { installed_packages } => {
+                journal.commit = Some(installed_packages)
+            }
+        }
+    }
+    Ok(journal)
+}
+
+/// Removes `.atxpkg_delete` files left behind by `try_delete` (e.g. when the file was in use).
+fn remove_delete_leftovers(path: &str) {
+    let pattern = format!("{}.atxpkg_delete*", glob::Pattern::escape(path));
+    for leftover in glob::glob(&pattern).into_iter().flatten().flatten() {
+        log::debug!("removing leftover {}", leftover.display());
+        if let Err(err) = std::fs::remove_file(&leftover) {
+            log::warn!("failed to remove {}: {err}", leftover.display());
+        }
+    }
+}
+
+/// Finishes operations interrupted by a crash: files of uncommitted ones are reverted and
+/// `installed_packages` are reconciled with the journaled state. Returns whether anything was
+/// recovered (and the database should be saved).
+pub fn recover(
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+    tmp_dir_prefix: &str,
+) -> anyhow::Result<bool> {
+    let mut journals = vec![];
+    for journal_dir in find_journals(tmp_dir_prefix)? {
+        let journal = read_journal(&journal_dir)?;
+        journals.push((journal_dir, journal));
+    }
+    journals.sort_by(|(_, a), (_, b)| a.t.total_cmp(&b.t));
+
+    let mut recovered = false;
+    for (journal_dir, journal) in journals {
+        let unfinished = journal.is_unfinished(installed_packages);
+        if let Some(committed_packages) = journal.commit {
+            // database still in the state from before the transaction means it didn't get saved,
+            // otherwise it's been saved (and possibly changed further by the caller)
+            if unfinished {
+                log::info!("completing interrupted operation");
+                status!("completing interrupted operation (database updated)");
+                *installed_packages = committed_packages;
+                recovered = true;
+            }
+        } else {
+            log::info!(
+                "reverting interrupted operation ({} changes)",
+                journal.undo_log.len()
+            );
+            status!(
+                "reverting interrupted operation ({} changes)",
+                journal.undo_log.len()
+            );
+            let err_count = revert_changes(&journal.undo_log);
+            if err_count > 0 {
+                anyhow::bail!(Error::Interrupted(format!(
+                    "failed to restore {err_count} paths, journal kept in {}",
+                    as_unix_path(&journal_dir)
+                )));
+            }
+            if let Some(begin_packages) = journal.installed_packages {
+                *installed_packages = begin_packages;
+            }
+            recovered = true;
+        }
+        for undo in &journal.undo_log {
+            remove_delete_leftovers(undo.path());
+        }
+        std::fs::remove_dir_all(&journal_dir)?;
+    }
+
+    Ok(recovered)
+}
+
+fn get_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Packages whose installed version differs between `before` and `after`.
+fn get_package_changes(
+    before: &HashMap<String, InstalledPackage>,
+    after: &HashMap<String, InstalledPackage>,
+) -> Vec<PackageChange> {
+    before
+        .keys()
+        .chain(after.keys())
+        .unique()
+        .sorted()
+        .filter_map(|name| {
+            let version_old = before.get(name).map(|x| x.version.clone());
+            let version_new = after.get(name).map(|x| x.version.clone());
+            (version_old != version_new).then(|| PackageChange {
+                name: name.clone(),
+                version_old,
+                version_new,
+            })
+        })
+        .collect()
+}
+
+pub fn get_history(history_fn: &str) -> anyhow::Result<Vec<HistoryEntry>> {
+    if !Path::new(history_fn).exists() {
+        return Ok(vec![]);
+    }
+    let mut ret = vec![];
+    for line in std::fs::read_to_string(history_fn)?.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(entry) => ret.push(entry),
+            Err(err) => log::warn!("skipping invalid history entry in {history_fn}: {err}"),
+        }
+    }
+    Ok(ret)
+}
+
+fn add_history_entry(history_fn: &str, mut entry: HistoryEntry) -> anyhow::Result<()> {
+    entry.id = get_history(history_fn)?.last().map_or(1, |x| x.id + 1);
+    let mut line = serde_json::to_string(&entry)?;
+    line.push('\n');
+    let mut f = File::options().create(true).append(true).open(history_fn)?;
+    f.write_all(line.as_bytes())?;
+    f.sync_data()?;
+    Ok(())
+}
+
+/// Whether there's an operation left for `recover` to finish given the saved
+/// `installed_packages`.
+pub fn needs_recover(
+    tmp_dir_prefix: &str,
+    installed_packages: &HashMap<String, InstalledPackage>,
+) -> anyhow::Result<bool> {
+    for journal_dir in find_journals(tmp_dir_prefix)? {
+        if read_journal(&journal_dir)?.is_unfinished(installed_packages) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Removes journals of committed operations once their database has been saved as
+/// `installed_packages`.
+pub fn remove_finished_journals(
+    tmp_dir_prefix: &str,
+    installed_packages: &HashMap<String, InstalledPackage>,
+) -> anyhow::Result<()> {
+    for journal_dir in find_journals(tmp_dir_prefix)? {
+        if !read_journal(&journal_dir)?.is_unfinished(installed_packages) {
+            log::debug!("removing journal {}", as_unix_path(&journal_dir));
+            std::fs::remove_dir_all(&journal_dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// Lock of atxpkg root dir, held until dropped. The lock is released by the OS should the process
+/// die so it can never be left stale, only the holder info can.
+pub struct RootDirLock {
+    _file: File,
+    info_fn: Option<String>,
+}
+
+impl Drop for RootDirLock {
+    fn drop(&mut self) {
+        if let Some(info_fn) = &self.info_fn
+            && let Err(err) = std::fs::remove_file(info_fn)
+        {
+            log::warn!("failed to remove {info_fn}: {err}");
+        }
+    }
+}
+
+fn describe_lock_holder(info: &str) -> String {
+    match info.trim().split_once(' ') {
+        Some((pid, command)) => format!("pid {pid} ({command})"),
+        None => format!("pid {}", info.trim()),
+    }
+}
+
+/// Locks the root dir, exclusively for commands changing anything, shared for read-only ones.
+/// Exclusive holder records its pid and `command` so that others can tell who's blocking them.
+pub fn lock_root_dir(
+    root_dir: &str,
+    exclusive: bool,
+    wait: bool,
+    command: &str,
+) -> anyhow::Result<RootDirLock> {
+    let lock_fn = format!("{root_dir}/atxpkg.lock");
+    let info_fn = format!("{root_dir}/atxpkg.lock.info");
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_fn)?;
+
+    let res = match exclusive {
+        true => file.try_lock(),
+        false => file.try_lock_shared(),
+    };
+    match res {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            let holder = match std::fs::read_to_string(&info_fn) {
+                Ok(info) => describe_lock_holder(&info),
+                Err(_) => "another atxpkg process (read-only command)".to_string(),
+            };
+            if !wait {
+                anyhow::bail!(Error::Locked(format!(
+                    "atxpkg is locked by {holder}, use --wait to wait for it"
+                )));
+            }
+            log::info!("waiting for lock held by {holder}");
+            reporter().warning(&format!("waiting for lock held by {holder}"));
+            match exclusive {
+                true => file.lock()?,
+                false => file.lock_shared()?,
+            }
+        }
+        Err(std::fs::TryLockError::Error(err)) => return Err(err.into()),
+    }
+
+    // nobody holds the lock exclusively now so the info is a leftover of a dead process
+    if let Ok(info) = std::fs::read_to_string(&info_fn) {
+        log::warn!("removing stale lock of {}", describe_lock_holder(&info));
+        std::fs::remove_file(&info_fn)?;
+    }
+
+    let info_fn = match exclusive {
+        true => {
+            std::fs::write(&info_fn, format!("{} {command}", std::process::id()))?;
+            Some(info_fn)
+        }
+        false => None,
+    };
+
+    Ok(RootDirLock {
+        _file: file,
+        info_fn,
+    })
 }
 
 pub fn get_installed_packages(db_fn: &str) -> anyhow::Result<HashMap<String, InstalledPackage>> {
@@ -77,6 +1290,32 @@ pub fn get_installed_packages(db_fn: &str) -> anyhow::Result<HashMap<String, Ins
     if !Path::new(db_fn).exists() {
         return Ok(HashMap::new());
     }
+    let err = match read_installed_packages(db_fn) {
+        Ok(installed_packages) => return Ok(installed_packages),
+        Err(err) => err,
+    };
+    for i in 1..=DB_BACKUP_COUNT {
+        let backup_fn = format!("{db_fn}.{i}");
+        if !Path::new(&backup_fn).exists() {
+            continue;
+        }
+        match read_installed_packages(&backup_fn) {
+            Ok(installed_packages) => {
+                log::error!("failed to read {db_fn}: {err}, using backup {backup_fn}");
+                reporter().warning(&format!(
+                    "!!! WARNING !!!\nfailed to read {db_fn}: {err}\nusing backup {backup_fn}, changes done since it was saved are lost!"
+                ));
+                return Ok(installed_packages);
+            }
+            Err(backup_err) => log::warn!("failed to read {backup_fn}: {backup_err}"),
+        }
+    }
+    Err(err.context(format!(
+        "failed to read {db_fn} (and no valid backup found)"
+    )))
+}
+
+fn read_installed_packages(db_fn: &str) -> anyhow::Result<HashMap<String, InstalledPackage>> {
     Ok(serde_json::from_reader(BufReader::new(File::open(db_fn)?))?)
 }
 
@@ -88,14 +1327,70 @@ pub fn save_installed_packages(
         "saving {} installed packages to {db_fn}",
         installed_packages.len()
     );
-    let mut f = BufWriter::new(File::create(db_fn)?);
+    let mut data = vec![];
     let encoder = serde_json::ser::PrettyFormatter::with_indent(b"  ");
-    let mut ser = serde_json::Serializer::with_formatter(&mut f, encoder);
+    let mut ser = serde_json::Serializer::with_formatter(&mut data, encoder);
     installed_packages.serialize(&mut ser)?;
-    f.flush()?;
+    if std::fs::read(db_fn).is_ok_and(|x| x == data) {
+        log::debug!("{db_fn} unchanged");
+        return Ok(());
+    }
+
+    // never leave truncated db behind - write it aside and replace the original in one step
+    let tmp_fn = format!("{db_fn}.tmp");
+    let mut f = File::create(&tmp_fn)?;
+    f.write_all(&data)?;
+    f.sync_all()?;
+    drop(f);
+
+    if Path::new(db_fn).exists() {
+        for i in (1..DB_BACKUP_COUNT).rev() {
+            let backup_fn = format!("{db_fn}.{i}");
+            if Path::new(&backup_fn).exists() {
+                std::fs::rename(&backup_fn, format!("{db_fn}.{}", i + 1))?;
+            }
+        }
+        std::fs::copy(db_fn, format!("{db_fn}.1"))?;
+    }
+    std::fs::rename(&tmp_fn, db_fn)?;
+
+    #[cfg(unix)]
+    if let Some(dir) = Path::new(db_fn).parent()
+        && let Err(err) = File::open(dir).and_then(|x| x.sync_all())
+    {
+        log::warn!("failed to sync {}: {err}", dir.display());
+    }
+
+    Ok(())
+}
+
+pub fn get_global_channel(channel_fn: &str) -> anyhow::Result<Option<Channel>> {
+    if !Path::new(channel_fn).exists() {
+        return Ok(None);
+    }
+    Ok(Some(std::fs::read_to_string(channel_fn)?.trim().parse()?))
+}
+
+pub fn save_global_channel(channel: Channel, channel_fn: &str) -> anyhow::Result<()> {
+    log::debug!("saving global channel {channel} to {channel_fn}");
+    std::fs::write(channel_fn, format!("{channel}\n"))?;
     Ok(())
 }
 
+/// Returns name of the package in given channel if such package is available, otherwise the name unchanged.
+fn get_channel_package_name(
+    package_name: &str,
+    channel: Option<Channel>,
+    available_packages: &HashMap<String, Vec<String>>,
+) -> String {
+    match channel {
+        Some(channel) if available_packages.contains_key(&channel.package_name(package_name)) => {
+            channel.package_name(package_name)
+        }
+        _ => package_name.to_string(),
+    }
+}
+
 fn get_available_packages(
     repos: Vec<String>,
     offline: bool,
@@ -103,7 +1398,7 @@ fn get_available_packages(
 ) -> anyhow::Result<HashMap<String, Vec<String>>> {
     log::debug!("getting available packages from {repos:?}");
 
-    let mb = indicatif::MultiProgress::new();
+    let progress_group = reporter().progress_group();
 
     let ret = {
         let (tx, rx) = std::sync::mpsc::channel();
@@ -113,17 +1408,12 @@ fn get_available_packages(
                     continue;
                 }
                 let tx = &tx;
-                let mb = &mb;
+                let progress_group = &progress_group;
                 scope.execute(move || {
                     let res = (|| {
-                        let pb = make_progress_bar(
-                            0,
-                            &repo,
-                            "{spinner} {prefix} [{wide_bar}] {bytes}/{total_bytes} ({bytes_per_sec})",
-                        )?;
-                        mb.add(pb.clone());
-                        let listing = get_repo_listing(&repo, unverified_ssl, Some(&pb));
-                        pb.finish();
+                        let progress = progress_group.progress(&repo, "", ProgressUnit::Bytes, 0);
+                        let listing = get_repo_listing(&repo, unverified_ssl, Some(&*progress));
+                        progress.finish();
                         anyhow::Ok(
                             listing?
                                 .into_iter()
@@ -159,10 +1449,10 @@ fn get_available_packages(
 
         if !failed_repos.is_empty() {
             log::warn!("Failed to read from {} repositories, package list may be incomplete", failed_repos.len());
-            eprintln!("WARNING: Failed to read from some repositories. Package list may be incomplete.");
-            for repo_err in &failed_repos {
-                eprintln!("  {}", repo_err);
-            }
+            reporter().warning(&format!(
+                "WARNING: Failed to read from some repositories. Package list may be incomplete.\n  {}",
+                failed_repos.join("\n  ")
+            ));
         }
 
         anyhow::Ok(ret)
@@ -172,51 +1462,75 @@ fn get_available_packages(
     .into_group_map();
 
     //mb.clear();
-    eprintln!();
+    progress_group.finish();
 
     Ok(ret)
 }
 
 fn is_valid_package_fn(fn_: &str) -> bool {
-    let re = lazy_regex::regex!(r"[\w\-\.]+-[\d.]+-\d+\.atxpkg\.zip");
-    re.is_match(fn_)
+    if !fn_.ends_with(".atxpkg.zip") {
+        return false;
+    }
+    let (name, version) = split_package_name_version(fn_);
+    let re = lazy_regex::regex!(r"^[\w\-\.]+$");
+    re.is_match(&name) && version.parse::<Version>().is_ok()
 }
 
 fn is_url(s: &str) -> bool {
     s.starts_with("http://") || s.starts_with("https://")
 }
 
+fn check_overwrite_globs(overwrite: &[String]) -> anyhow::Result<()> {
+    for x in overwrite {
+        if let Err(err) = glob::Pattern::new(x) {
+            anyhow::bail!("invalid overwrite pattern {x}: {err}");
+        }
+    }
+    Ok(())
+}
+
+fn is_overwrite_allowed(fn_: &str, overwrite: &[String]) -> bool {
+    overwrite
+        .iter()
+        .any(|x| glob::Pattern::new(x).is_ok_and(|x| x.matches(fn_)))
+}
+
 fn get_repo_listing(
     repo: &str,
     unverified_ssl: bool,
-    progress_bar: Option<&indicatif::ProgressBar>,
+    progress: Option<&dyn Progress>,
 ) -> anyhow::Result<Vec<String>> {
     log::info!("getting repo listing from {repo}");
     if is_url(repo) {
-        return get_repo_listing_http(repo, unverified_ssl, progress_bar);
+        return get_repo_listing_http(repo, unverified_ssl, progress);
     }
-    get_repo_listing_dir(repo, progress_bar)
+    get_repo_listing_dir(repo, progress)
 }
 
 fn get_repo_listing_http(
     url: &str,
     unverified_ssl: bool,
-    progress_bar: Option<&indicatif::ProgressBar>,
+    progress: Option<&dyn Progress>,
 ) -> anyhow::Result<Vec<String>> {
     let client = reqwest::blocking::ClientBuilder::new()
         .danger_accept_invalid_certs(unverified_ssl)
         .build()?;
     let resp = client.get(url).send()?;
     if !resp.status().is_success() {
-        anyhow::bail!("Failed to download listing: {}", resp.status());
+        anyhow::bail!(Error::Network(format!(
+            "Failed to download listing: {}",
+            resp.status()
+        )));
     };
 
     let total_size = resp.content_length().unwrap_or(0);
 
-    let mut reader: Box<dyn std::io::Read> = if let Some(pb) = progress_bar {
-        pb.set_length(total_size);
-        pb.enable_steady_tick(Duration::from_millis(200));
-        Box::new(pb.wrap_read(resp))
+    let mut reader: Box<dyn std::io::Read> = if let Some(progress) = progress {
+        progress.set_length(total_size);
+        Box::new(ProgressReader {
+            inner: resp,
+            progress,
+        })
     } else {
         Box::new(resp)
     };
@@ -237,14 +1551,16 @@ fn get_repo_listing_http(
 
 fn get_repo_listing_dir(
     path: &str,
-    progress_bar: Option<&indicatif::ProgressBar>,
+    progress: Option<&dyn Progress>,
 ) -> anyhow::Result<Vec<String>> {
     let mut ret = Vec::new();
 
-    let walker = walkdir::WalkDir::new(path).into_iter();
-    let iter: Box<dyn Iterator<Item = _>> = if let Some(pb) = progress_bar {
-        pb.enable_steady_tick(Duration::from_millis(200));
-        Box::new(pb.wrap_iter(walker))
+    // rollback dir of the cache is only used as a repo by `with_rollback_repo`
+    let walker = walkdir::WalkDir::new(path).into_iter().filter_entry(|x| {
+        !(x.depth() == 1 && x.file_type().is_dir() && x.file_name() == "rollback")
+    });
+    let iter: Box<dyn Iterator<Item = _>> = if let Some(progress) = progress {
+        Box::new(walker.inspect(move |_| progress.inc(1)))
     } else {
         Box::new(walker)
     };
@@ -263,11 +1579,21 @@ fn get_repo_listing_dir(
     Ok(ret)
 }
 
+#[allow(clippy::collapsible_if)]
+/// Local package file of `url` (the file itself or its cached download) if it exists.
+fn get_local_package_fn(url: &str, cache_dir: &str) -> Option<String> {
+    let fn_ = match is_url(url) {
+        true => format!("{cache_dir}/{}", get_package_fn(url)?),
+        false => url.to_string(),
+    };
+    Path::new(&fn_).exists().then_some(fn_)
+}
+
 fn download_package_if_needed(
     url: &str,
     cache_dir: &str,
     unverified_ssl: bool,
-    progress_bar: Option<&indicatif::ProgressBar>,
+    progress: Option<&dyn Progress>,
 ) -> anyhow::Result<String> {
     if !is_url(url) {
         return Ok(url.to_string());
@@ -294,10 +1620,9 @@ fn download_package_if_needed(
                 .headers()
                 .get("Accept-Ranges")
                 .is_some_and(|v| v == "bytes")
+            && let Ok(metadata) = std::fs::metadata(&fn_temp)
         {
-            if let Ok(metadata) = std::fs::metadata(&fn_temp) {
-                resume_from = metadata.len();
-            }
+            resume_from = metadata.len();
         }
     }
 
@@ -313,19 +1638,23 @@ fn download_package_if_needed(
 
     let resp = req.send()?;
     if !resp.status().is_success() {
-        anyhow::bail!("Failed to download file: {}", resp.status());
+        anyhow::bail!(Error::Network(format!(
+            "Failed to download file: {}",
+            resp.status()
+        )));
     };
 
     let size_to_download = resp.content_length().unwrap_or(0);
 
     let mut reader: Box<dyn std::io::Read> = Box::new(resp);
 
-    if let Some(pb) = progress_bar {
-        pb.set_length(resume_from + size_to_download);
-        pb.set_position(resume_from);
-        pb.reset_eta();
-        pb.enable_steady_tick(Duration::from_millis(200));
-        reader = Box::new(pb.wrap_read(reader));
+    if let Some(progress) = progress {
+        progress.set_length(resume_from + size_to_download);
+        progress.set_position(resume_from);
+        reader = Box::new(ProgressReader {
+            inner: reader,
+            progress,
+        });
     }
 
     let f = std::fs::OpenOptions::new()
@@ -336,8 +1665,8 @@ fn download_package_if_needed(
     std::io::copy(&mut reader, &mut f)?;
     f.flush()?;
 
-    if let Some(pb) = progress_bar {
-        pb.finish();
+    if let Some(progress) = progress {
+        progress.finish();
     }
 
     log::trace!("renaming {fn_temp} to {fn_}");
@@ -381,7 +1710,7 @@ pub fn list_available(
     repos: Vec<String>,
     offline: bool,
     unverified_ssl: bool,
-) -> anyhow::Result<Vec<(String, String)>> {
+) -> anyhow::Result<Vec<AvailablePackage>> {
     let mut ret = Vec::new();
     let available_packages = get_available_packages(repos, offline, unverified_ssl)?;
 
@@ -393,16 +1722,22 @@ pub fn list_available(
         keys.sort();
         keys.dedup();
         for k in keys {
-            ret.push((k.clone(), String::new()));
+            ret.push(AvailablePackage {
+                name: k,
+                version: None,
+            });
         }
     } else {
         for p in &packages {
             let Some(urls) = available_packages.get(p) else {
-                anyhow::bail!("package {p} not available");
+                anyhow::bail!(Error::NotAvailable(format!("package {p} not available")));
             };
             for url in urls {
                 let version = get_package_version(&get_package_fn(url).unwrap());
-                ret.push((p.clone(), version.clone()));
+                ret.push(AvailablePackage {
+                    name: p.clone(),
+                    version: Some(version),
+                });
             }
         }
         ret.sort_unstable();
@@ -410,17 +1745,200 @@ pub fn list_available(
     Ok(ret)
 }
 
+/// Installed packages sorted by name.
+pub fn list_installed(
+    installed_packages: &HashMap<String, InstalledPackage>,
+    channel: Option<Channel>,
+) -> Vec<ListedPackage> {
+    installed_packages
+        .iter()
+        .sorted_unstable_by_key(|x| x.0)
+        .map(|(package_name, package_info)| ListedPackage {
+            name: package_name.clone(),
+            version: package_info.version.clone(),
+            hold: package_info.hold.clone(),
+            channel: package_info.channel.or(channel),
+        })
+        .collect()
+}
+
+pub fn get_package_details(
+    package: &str,
+    installed_packages: &HashMap<String, InstalledPackage>,
+    prefix: &str,
+    repos: Vec<String>,
+    offline: bool,
+    unverified_ssl: bool,
+    cache_dir: &str,
+) -> anyhow::Result<PackageDetails> {
+    let (package_name, constraint) = parse_package_spec(package)?;
+    let available_packages = get_available_packages(repos, offline, unverified_ssl)?;
+    let available_versions = available_packages
+        .get(&package_name)
+        .cloned()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|url| Some(get_package_version(&get_package_fn(url)?)))
+        .unique()
+        .sorted_by(|a, b| compare_versions(a, b))
+        .collect::<Vec<_>>();
+
+    if let Some(installed_package) = installed_packages.get(&package_name)
+        && constraint
+            .as_ref()
+            .is_none_or(|c| c.matches(&installed_package.version))
+    {
+        let (mut dirs, mut files, mut size) = (0, 0, 0);
+        for (fn_, md5sum) in &installed_package.md5sums {
+            if md5sum.is_none() {
+                dirs += 1;
+                continue;
+            }
+            files += 1;
+            if let Ok(meta) = std::fs::metadata(format!("{prefix}/{fn_}")) {
+                size += meta.len();
+            }
+        }
+        return Ok(PackageDetails {
+            name: package_name,
+            version: installed_package.version.clone(),
+            installed: true,
+            t: installed_package.t,
+            files,
+            dirs,
+            size,
+            backup: installed_package.backup.clone().unwrap_or_default(),
+            repo: installed_package.repo.clone(),
+            newer_versions: available_versions
+                .into_iter()
+                .filter(|x| {
+                    compare_versions(x, &installed_package.version) == std::cmp::Ordering::Greater
+                })
+                .collect(),
+        });
+    }
+
+    let url = get_package_url(&package_name, constraint.as_slice(), &available_packages)?;
+    let version = get_package_version(&get_package_fn(&url).unwrap());
+    let local_fn = download_package_if_needed(&url, cache_dir, unverified_ssl, None)?;
+    let listing = get_package_listing(&local_fn)?;
+
+    Ok(PackageDetails {
+        name: package_name,
+        version: version.clone(),
+        installed: false,
+        t: None,
+        files: listing.files.len(),
+        dirs: listing.dirs.len(),
+        size: listing.files.iter().map(|(_, size)| size).sum(),
+        backup: listing.backup.unwrap_or_default(),
+        repo: get_origin_repo(&url, &available_packages, cache_dir),
+        newer_versions: available_versions
+            .into_iter()
+            .filter(|x| compare_versions(x, &version) == std::cmp::Ordering::Greater)
+            .collect(),
+    })
+}
+
+pub fn get_package_files(
+    package: &str,
+    installed_packages: &HashMap<String, InstalledPackage>,
+    repos: Vec<String>,
+    offline: bool,
+    unverified_ssl: bool,
+    cache_dir: &str,
+) -> anyhow::Result<Vec<PackageFile>> {
+    let local_fn = if package.ends_with(".atxpkg.zip") && Path::new(package).is_file() {
+        package.to_string()
+    } else {
+        let (package_name, constraint) = parse_package_spec(package)?;
+        if let Some(installed_package) = installed_packages.get(&package_name)
+            && constraint
+                .as_ref()
+                .is_none_or(|c| c.matches(&installed_package.version))
+        {
+            let backup = installed_package.backup.clone().unwrap_or_default();
+            return Ok(installed_package
+                .md5sums
+                .iter()
+                .map(|(fn_, md5sum)| PackageFile {
+                    path: fn_.clone(),
+                    is_dir: md5sum.is_none(),
+                    is_backup: backup.contains(fn_),
+                })
+                .sorted_by(|a, b| a.path.cmp(&b.path))
+                .collect());
+        }
+        let available_packages = get_available_packages(repos, offline, unverified_ssl)?;
+        let url = get_package_url(&package_name, constraint.as_slice(), &available_packages)?;
+        download_package_if_needed(&url, cache_dir, unverified_ssl, None)?
+    };
+
+    let listing = get_package_listing(&local_fn)?;
+    let backup = listing.backup.unwrap_or_default();
+    Ok(listing
+        .dirs
+        .into_iter()
+        .map(|path| PackageFile {
+            path,
+            is_dir: true,
+            is_backup: false,
+        })
+        .chain(listing.files.into_iter().map(|(path, _)| PackageFile {
+            is_backup: backup.contains(&path),
+            path,
+            is_dir: false,
+        }))
+        .sorted_by(|a, b| a.path.cmp(&b.path))
+        .collect())
+}
+
+fn get_package_url(
+    package_name: &str,
+    constraints: &[VersionConstraint],
+    available_packages: &HashMap<String, Vec<String>>,
+) -> anyhow::Result<String> {
+    let Some(urls) = available_packages.get(package_name) else {
+        anyhow::bail!(Error::NotAvailable(format!(
+            "package {package_name} not available"
+        )));
+    };
+    let matching_urls = urls
+        .iter()
+        .filter(|url| {
+            get_package_fn(url).is_some_and(|fn_| {
+                let version = get_package_version(&fn_);
+                constraints.iter().all(|c| c.matches(&version))
+            })
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    let Some(url) = get_max_version_url(matching_urls) else {
+        let available_versions = urls
+            .iter()
+            .filter_map(|url| Some(get_package_version(&get_package_fn(url)?)))
+            .unique()
+            .sorted_by(|a, b| compare_versions(a, b))
+            .join(", ");
+        anyhow::bail!(Error::NotAvailable(format!(
+            "package {} not available (available versions: {available_versions})",
+            format_package_spec(package_name, constraints)
+        )));
+    };
+    Ok(url)
+}
+
 fn split_package_name_version(pkg_spec: &str) -> (String, String) {
-    let re = lazy_regex::regex!(r"^(.+?)(?:-([\d.-]+))?(?:\.atxpkg\.zip)?$");
-    let matches = re.captures(pkg_spec);
+    let pkg_spec = pkg_spec.strip_suffix(".atxpkg.zip").unwrap_or(pkg_spec);
 
-    if let Some(caps) = matches {
-        let name = caps.get(1).map_or("", |m| m.as_str()).to_string();
-        let version = caps.get(2).map_or("", |m| m.as_str()).to_string();
-        return (name, version);
+    // the version starts with a digit (or epoch) and ends with numeric release, the name is greedy so that
+    // names containing digits after a dash (e.g. atx-300-base) are not mistaken for versions
+    let re = lazy_regex::regex!(r"^(.+)-((?:\d+!)?\d[0-9A-Za-z_.+]*(?:-[0-9A-Za-z_.+]+)*-\d+)$");
+    if let Some(caps) = re.captures(pkg_spec) {
+        return (caps[1].to_string(), caps[2].to_string());
     }
 
-    (String::new(), String::new())
+    (pkg_spec.to_string(), String::new())
 }
 
 fn get_package_fn(url: &str) -> Option<String> {
@@ -428,6 +1946,30 @@ fn get_package_fn(url: &str) -> Option<String> {
     parts.last().map(|x| x.to_string())
 }
 
+fn get_repo(url: &str) -> Option<String> {
+    url.rsplit_once('/').map(|(repo, _)| repo.to_string())
+}
+
+/// Repo the package at `url` comes from. A copy in the cache (including the rollback copies) is
+/// traced back to a repo offering the same package file, `None` if there's no such repo (offline).
+fn get_origin_repo(
+    url: &str,
+    available_packages: &HashMap<String, Vec<String>>,
+    cache_dir: &str,
+) -> Option<String> {
+    let is_cached = |url: &str| url.starts_with(&format!("{cache_dir}/"));
+    if !is_cached(url) {
+        return get_repo(url);
+    }
+    let package_fn = get_package_fn(url)?;
+    available_packages
+        .get(&get_package_name(&package_fn))?
+        .iter()
+        .filter(|x| !is_cached(x) && get_package_fn(x).as_ref() == Some(&package_fn))
+        .filter_map(|x| get_repo(x))
+        .min()
+}
+
 fn get_package_name(fn_: &str) -> String {
     let (name, _) = split_package_name_version(fn_);
     name
@@ -443,99 +1985,256 @@ pub fn if_installed(
     installed_packages: &HashMap<String, InstalledPackage>,
 ) -> anyhow::Result<()> {
     for p in &packages {
-        let (package_name, package_version) = split_package_name_version(p);
+        let (package_name, constraint) = parse_package_spec(p)?;
         let Some(installed_package) = installed_packages.get(&package_name) else {
-            anyhow::bail!("package {package_name} not installed");
+            anyhow::bail!(Error::NotInstalled(format!(
+                "package {package_name} not installed"
+            )));
         };
-        if !package_version.is_empty() && package_version != installed_package.version {
-            anyhow::bail!("package {package_name}-{package_version} not installed");
+        if let Some(c) = &constraint
+            && !c.matches(&installed_package.version)
+        {
+            anyhow::bail!(Error::NotInstalled(format!(
+                "package {package_name}{c} not installed (installed version: {})",
+                installed_package.version
+            )));
         }
     }
     Ok(())
 }
 
+pub fn hold_packages(
+    packages: Vec<String>,
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+    max_version: Option<String>,
+) -> anyhow::Result<()> {
+    let constraint = match &max_version {
+        Some(max_version) => Some(VersionConstraint {
+            op: ConstraintOp::Le,
+            version: max_version.parse()?,
+        }),
+        None => None,
+    };
+    for p in &packages {
+        let Some(installed_package) = installed_packages.get(p) else {
+            anyhow::bail!(Error::NotInstalled(format!("package {p} not installed")));
+        };
+        // holding below the installed version would mean a downgrade
+        if let Some(c) = &constraint
+            && !c.matches(&installed_package.version)
+        {
+            anyhow::bail!(
+                "package {p}-{} is newer than hold version {}",
+                installed_package.version,
+                c.version
+            );
+        }
+    }
+
+    for p in &packages {
+        installed_packages.get_mut(p).unwrap().hold = Some(Hold {
+            max_version: max_version.clone(),
+        });
+    }
+    Ok(())
+}
+
+pub fn unhold_packages(
+    packages: Vec<String>,
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+) -> anyhow::Result<()> {
+    for p in &packages {
+        let Some(installed_package) = installed_packages.get(p) else {
+            anyhow::bail!(Error::NotInstalled(format!("package {p} not installed")));
+        };
+        if installed_package.hold.is_none() {
+            anyhow::bail!("package {p} not held");
+        }
+    }
+
+    for p in &packages {
+        installed_packages.get_mut(p).unwrap().hold = None;
+    }
+    Ok(())
+}
+
 pub fn clean_cache(cache_dir: &str) -> anyhow::Result<()> {
     let files = std::fs::read_dir(cache_dir)?;
     for file in files {
         let file = file?;
         let file_path = file.path();
-        if file_path.file_name().is_some() {
+        // rollback dir is kept so that rollback works even after cleaning the cache
+        if file_path.file_name().is_some() && !file.file_type()?.is_dir() {
             let file_path_str = file_path.to_string_lossy().into_owned();
             std::fs::remove_file(&file_path)?;
-            eprintln!("D {file_path_str}");
+            status!("D {file_path_str}");
         }
     }
     Ok(())
 }
 
-pub fn install_packages(
+#[allow(clippy::too_many_arguments)]
+pub fn install_packages(
+    packages: Vec<String>,
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+    prefix: &str,
+    repos: Vec<String>,
+    channel: Option<Channel>,
+    options: &Options,
+    cache_dir: &str,
+    tmp_dir_prefix: &str,
+    history_fn: Option<&str>,
+) -> anyhow::Result<bool> {
+    with_transaction(
+        installed_packages,
+        tmp_dir_prefix,
+        history_fn,
+        |installed_packages, transaction| {
+            check_overwrite_globs(&options.overwrite)?;
+            let package_installs = get_package_installs(
+                packages,
+                installed_packages,
+                repos,
+                channel,
+                options,
+                cache_dir,
+            )?;
+            reporter().plan(&get_install_plan(
+                &package_installs,
+                installed_packages,
+                options.download_only,
+            ));
+            if !confirm(options.yes, options.no, true)? {
+                return Ok(false);
+            }
+            install_packages_in_transaction(
+                package_installs,
+                installed_packages,
+                prefix,
+                options,
+                cache_dir,
+                tmp_dir_prefix,
+                transaction,
+            )
+        },
+    )
+}
+
+/// Package to install, see `get_package_installs`.
+struct PackageInstall {
+    name: String,
+    version: String,
+    url: String,
+    /// Origin repo of `url`, see `get_origin_repo`.
+    repo: Option<String>,
+}
+
+fn get_package_installs(
     packages: Vec<String>,
-    installed_packages: &mut HashMap<String, InstalledPackage>,
-    prefix: &str,
+    installed_packages: &HashMap<String, InstalledPackage>,
     repos: Vec<String>,
-    force: bool,
-    offline: bool,
-    yes: bool,
-    no: bool,
-    download_only: bool,
-    unverified_ssl: bool,
+    channel: Option<Channel>,
+    options: &Options,
     cache_dir: &str,
-    tmp_dir_prefix: &str,
-) -> anyhow::Result<bool> {
-    let available_packages = get_available_packages(repos, offline, unverified_ssl)?;
+) -> anyhow::Result<Vec<PackageInstall>> {
+    let available_packages =
+        get_available_packages(repos, options.offline, options.unverified_ssl)?;
 
+    let mut package_specs = Vec::with_capacity(packages.len());
     for p in &packages {
-        let package_name = get_package_name(p);
-        if installed_packages.contains_key(&package_name) && !force && !download_only {
-            anyhow::bail!("package {package_name} already installed");
+        let (mut package_name, constraint) = parse_package_spec(p)?;
+        if constraint.is_none() && !installed_packages.contains_key(&package_name) {
+            package_name = get_channel_package_name(&package_name, channel, &available_packages);
         }
-        if !available_packages.contains_key(&package_name) {
-            anyhow::bail!("unable to find url for package {package_name}");
+        if installed_packages.contains_key(&package_name)
+            && !options.reinstall
+            && !options.download_only
+        {
+            anyhow::bail!(Error::Conflict(format!(
+                "package {package_name} already installed"
+            )));
         }
-    }
-
-    let mut urls_to_install = Vec::with_capacity(packages.len());
-    for p in &packages {
-        let (package_name, package_version) = split_package_name_version(p);
-        let package_urls = available_packages
-            .get(&package_name)
-            .expect("safe unwrap due to earlier check");
-        let url = if !package_version.is_empty() {
-            get_specific_version_url(package_urls.clone(), &package_version)
-        } else {
-            get_max_version_url(package_urls.clone())
+        if installed_packages.contains_key(&package_name) && options.adopt && !options.download_only
+        {
+            anyhow::bail!(
+                "--adopt only applies to packages being installed, {package_name} is installed already"
+            );
         }
-        .unwrap();
-        urls_to_install.push(url.clone());
-        let (package_name, package_version) =
-            split_package_name_version(&get_package_fn(&url).unwrap());
-        match download_only {
-            true => println!("download {package_name}-{package_version}"),
-            false => println!("install {package_name}-{package_version}"),
+        if !available_packages.contains_key(&package_name) {
+            anyhow::bail!(Error::NotAvailable(format!(
+                "unable to find url for package {package_name}"
+            )));
         }
+        package_specs.push((package_name, constraint));
     }
-    if no || !(yes || yes_no("continue?", "y")?) {
-        return Ok(false);
+
+    let mut ret = Vec::with_capacity(package_specs.len());
+    for (package_name, constraint) in &package_specs {
+        let url = get_package_url(package_name, constraint.as_slice(), &available_packages)?;
+        let (name, version) = split_package_name_version(&get_package_fn(&url).unwrap());
+        let repo = get_origin_repo(&url, &available_packages, cache_dir);
+        ret.push(PackageInstall {
+            name,
+            version,
+            url,
+            repo,
+        });
     }
 
-    let mb = indicatif::MultiProgress::new();
+    Ok(ret)
+}
+
+fn get_install_plan(
+    package_installs: &[PackageInstall],
+    installed_packages: &HashMap<String, InstalledPackage>,
+    download_only: bool,
+) -> Vec<PlannedChange> {
+    package_installs
+        .iter()
+        .map(|pi| PlannedChange {
+            action: match download_only {
+                true => PlanAction::Download,
+                false if installed_packages.contains_key(&pi.name) => PlanAction::Reinstall,
+                false => PlanAction::Install,
+            },
+            name: pi.name.clone(),
+            version: pi.version.clone(),
+            name_old: None,
+            version_old: None,
+            detail: None,
+        })
+        .collect()
+}
+
+/// Downloads and installs planned packages (see `get_package_installs`) without asking.
+fn install_packages_in_transaction(
+    package_installs: Vec<PackageInstall>,
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+    prefix: &str,
+    options: &Options,
+    cache_dir: &str,
+    tmp_dir_prefix: &str,
+    transaction: &mut Transaction,
+) -> anyhow::Result<bool> {
+    let progress_group = reporter().progress_group();
 
     let local_fns_to_install = {
         let (tx, rx) = std::sync::mpsc::channel();
         scoped_threadpool::Pool::new(MAX_CONCURRENT_DOWNLOADS).scoped(|scope| {
-            for url in &urls_to_install {
+            for pi in &package_installs {
                 let tx = &tx;
-                let mb = &mb;
+                let progress_group = &progress_group;
                 scope.execute(move || {
                     let res = (|| {
-                        let package_name = get_package_name(&get_package_fn(url).unwrap());
-                        let pb = make_progress_bar(
-                            0,
-                            &package_name,
-                            "{spinner} {prefix} [{wide_bar}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+                        let progress =
+                            progress_group.progress(&pi.name, "", ProgressUnit::Bytes, 0);
+                        let local_fn = download_package_if_needed(
+                            &pi.url,
+                            cache_dir,
+                            options.unverified_ssl,
+                            Some(&*progress),
                         )?;
-                        mb.add(pb.clone());
-                        download_package_if_needed(url, cache_dir, unverified_ssl, Some(&pb))
+                        anyhow::Ok((pi, local_fn))
                     })();
                     tx.send(res).unwrap();
                 });
@@ -547,114 +2246,369 @@ pub fn install_packages(
     }?;
 
     //mb.clear();
-    eprintln!();
+    progress_group.finish();
 
-    if download_only {
+    if options.download_only {
         return Ok(false);
     }
 
-    for local_fn in &local_fns_to_install {
-        let (package_name, package_version) =
-            split_package_name_version(&get_package_fn(local_fn).unwrap());
-        let package_info = install_package(local_fn, prefix, force, tmp_dir_prefix)?;
-        installed_packages.insert(package_name.clone(), package_info);
-        println!("{package_name}-{package_version} is now installed");
+    for (pi, local_fn) in &local_fns_to_install {
+        let mut package_info = if let Some(installed_package) = installed_packages.get(&pi.name) {
+            update_package_in_transaction(
+                local_fn,
+                &pi.name,
+                installed_package.clone(),
+                prefix,
+                &options.overwrite,
+                tmp_dir_prefix,
+                transaction,
+            )?
+        } else {
+            install_package(
+                local_fn,
+                prefix,
+                &options.overwrite,
+                options.adopt,
+                tmp_dir_prefix,
+                transaction,
+            )?
+        };
+        package_info.repo = pi.repo.clone();
+        installed_packages.insert(pi.name.clone(), package_info);
+        status!("{}-{} is now installed", pi.name, pi.version);
     }
 
     Ok(true)
 }
 
-fn yes_no(prompt: &str, default: &str) -> anyhow::Result<bool> {
-    if default.is_empty() && std::io::stdin().is_terminal() {
-        anyhow::bail!("input is not a tty");
-    };
+/// Asks whether to continue unless answered by the `yes`/`no` flags. `no` makes a dry run while
+/// answering no is `Error::Declined`.
+fn confirm(yes: bool, no: bool, default: bool) -> anyhow::Result<bool> {
+    if no {
+        return Ok(false);
+    }
+    if yes || reporter().confirm("continue?", default)? {
+        return Ok(true);
+    }
+    anyhow::bail!(Error::Declined)
+}
 
-    let question = match default {
-        "y" => format!("{prompt} [Y/n] "),
-        "n" => format!("{prompt} [y/N] "),
-        _ => format!("{prompt} [y/n] "),
-    };
+fn get_max_version_url(urls: Vec<String>) -> Option<String> {
+    let mut max_version_url: Option<(Version, String)> = None;
+    for url in urls {
+        let package_version = get_package_version(&get_package_fn(&url)?);
+        let package_version = match package_version.parse::<Version>() {
+            Ok(x) => x,
+            Err(err) => {
+                log::warn!("skipping {url}: {err}");
+                continue;
+            }
+        };
+        if let Some((max_version, _)) = &max_version_url
+            && package_version <= *max_version
+        {
+            continue;
+        }
+        max_version_url = Some((package_version, url));
+    }
+    max_version_url.map(|(_, url)| url)
+}
+
+/// Package version in the form of `[epoch!]version-release`.
+///
+/// Versions are compared segment by segment (similar to rpm/pacman), numeric segments are newer
+/// than alphanumeric ones (which compare alphabetically) and a trailing alphanumeric segment not
+/// preceded by a separator (or preceded by `-`) is a pre-release, so
+/// `1.0-beta < 1.0rc1 < 1.0 < 1.0.a < 1.0.1`. The release (after the last `-`) only decides
+/// between equal versions. The epoch allows resetting the version scheme.
+#[derive(Clone, Debug)]
+pub struct Version {
+    epoch: u64,
+    segments: Vec<VersionSegment>,
+    release: Vec<VersionSegment>,
+    original: String,
+}
+
+#[derive(Clone, Debug)]
+struct VersionSegment {
+    value: String,
+    is_numeric: bool,
+    separator: Option<char>,
+}
+
+impl VersionSegment {
+    fn cmp_value(&self, other: &Self) -> std::cmp::Ordering {
+        match (self.is_numeric, other.is_numeric) {
+            (true, true) => {
+                let a = self.value.trim_start_matches('0');
+                let b = other.value.trim_start_matches('0');
+                a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+            }
+            (true, false) => std::cmp::Ordering::Greater,
+            (false, true) => std::cmp::Ordering::Less,
+            (false, false) => self.value.cmp(&other.value),
+        }
+    }
+
+    fn is_pre_release(&self) -> bool {
+        !self.is_numeric && matches!(self.separator, None | Some('-'))
+    }
 
-    loop {
-        print!("{question}");
-        std::io::stdout().flush()?;
-
-        let mut ans = String::new();
-        std::io::stdin()
-            .read_line(&mut ans)
-            .expect("Failed to read line");
-        let ans = ans.trim().to_lowercase();
-
-        match ans.as_str() {
-            "y" | "yes" => return Ok(true),
-            "n" | "no" => return Ok(false),
-            "" => {
-                if default == "y" {
-                    return Ok(true);
-                } else if default == "n" {
-                    return Ok(false);
+    fn parse_all(s: &str) -> Vec<VersionSegment> {
+        let mut segments: Vec<VersionSegment> = vec![];
+        let mut separator = None;
+        for c in s.chars() {
+            if !c.is_ascii_alphanumeric() {
+                separator = Some(c);
+                continue;
+            }
+            match segments.last_mut() {
+                Some(last) if separator.is_none() && last.is_numeric == c.is_ascii_digit() => {
+                    last.value.push(c);
                 }
+                _ => segments.push(VersionSegment {
+                    value: c.to_string(),
+                    is_numeric: c.is_ascii_digit(),
+                    separator,
+                }),
             }
-            _ => println!("Invalid input. Please enter 'y' or 'n'."),
+            separator = None;
         }
+        segments
     }
-}
 
-fn get_max_version_url(urls: Vec<String>) -> Option<String> {
-    let mut max_version_url: Option<String> = None;
-    for url in urls {
-        let package_version = get_package_version(&get_package_fn(&url)?);
-        if let Some(max_version_url_) = &max_version_url {
-            let max_version = get_package_version(&get_package_fn(max_version_url_)?);
-            if compare_versions(&package_version, &max_version) == std::cmp::Ordering::Greater {
-                max_version_url = Some(url);
+    fn cmp_all(a: &[VersionSegment], b: &[VersionSegment]) -> std::cmp::Ordering {
+        use std::cmp::Ordering;
+
+        for x in a.iter().zip_longest(b.iter()) {
+            let ord = match x {
+                itertools::EitherOrBoth::Both(a, b) => a.cmp_value(b),
+                itertools::EitherOrBoth::Left(a) if a.is_pre_release() => Ordering::Less,
+                itertools::EitherOrBoth::Left(_) => Ordering::Greater,
+                itertools::EitherOrBoth::Right(b) if b.is_pre_release() => Ordering::Greater,
+                itertools::EitherOrBoth::Right(_) => Ordering::Less,
+            };
+            if ord != Ordering::Equal {
+                return ord;
             }
-        } else {
-            max_version_url = Some(url);
         }
+        Ordering::Equal
+    }
+}
+
+impl std::str::FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let re = lazy_regex::regex!(r"^(?:(\d+)!)?(\d[0-9A-Za-z_.+]*(?:-[0-9A-Za-z_.+]+)*)$");
+        let Some(caps) = re.captures(s) else {
+            anyhow::bail!("invalid version: {s}");
+        };
+        let epoch = match caps.get(1) {
+            Some(x) => x.as_str().parse()?,
+            None => 0,
+        };
+
+        let (version, release) = caps[2].rsplit_once('-').unwrap_or((&caps[2], ""));
+
+        Ok(Version {
+            epoch,
+            segments: VersionSegment::parse_all(version),
+            release: VersionSegment::parse_all(release),
+            original: s.to_string(),
+        })
     }
-    max_version_url
 }
 
-fn get_max_version(urls: Vec<String>) -> Option<String> {
-    Some(get_package_version(&get_package_fn(&get_max_version_url(
-        urls,
-    )?)?))
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.original)
+    }
 }
 
-fn split_ver(ver: &str) -> Vec<u64> {
-    let regex = lazy_regex::regex!(r"[.-]");
-    let parts = regex.split(ver).map(|x| x.to_string()).collect::<Vec<_>>();
-    parts
-        .into_iter()
-        .map(|x| x.parse::<u64>().unwrap())
-        .collect()
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| VersionSegment::cmp_all(&self.segments, &other.segments))
+            .then_with(|| VersionSegment::cmp_all(&self.release, &other.release))
+    }
 }
 
-fn compare_versions(v1: &str, v2: &str) -> std::cmp::Ordering {
-    let split_v1 = split_ver(v1);
-    let split_v2 = split_ver(v2);
-    split_v1.cmp(&split_v2)
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
-fn get_specific_version_url(urls: Vec<String>, version: &str) -> Option<String> {
-    for url in urls {
-        if get_package_version(&get_package_fn(&url)?) == version {
-            return Some(url.clone());
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl Version {
+    /// Returns true if the leading segments of the version are equal to those of `prefix`
+    /// (e.g. `6.3.2-1` starts with `6.3` but `6.30-1` does not).
+    fn starts_with(&self, prefix: &Version) -> bool {
+        let is_prefix = |a: &[VersionSegment], b: &[VersionSegment]| {
+            a.len() >= b.len()
+                && a.iter()
+                    .zip(b.iter())
+                    .all(|(a, b)| a.cmp_value(b) == std::cmp::Ordering::Equal)
+        };
+        self.epoch == prefix.epoch
+            && if prefix.release.is_empty() {
+                is_prefix(&self.segments, &prefix.segments)
+            } else {
+                VersionSegment::cmp_all(&self.segments, &prefix.segments)
+                    == std::cmp::Ordering::Equal
+                    && is_prefix(&self.release, &prefix.release)
+            }
+    }
+
+    /// Same as `starts_with` but pre-releases of `prefix` (e.g. `6.3rc1-1` for `6.3`) do not match.
+    fn is_release_of(&self, prefix: &Version) -> bool {
+        self.starts_with(prefix)
+            && !self
+                .segments
+                .get(prefix.segments.len())
+                .is_some_and(|x| x.is_pre_release())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConstraintOp {
+    Exact,
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Compatible,
+}
+
+/// Version requirement of a package spec, e.g. the `>=6.3` part of `atx300-base>=6.3`.
+///
+/// A constraint version without release matches all of its releases and patches so `=6.3` and
+/// `<=6.3` match `6.3.2-1` while `<6.3` does not. `~6.3` matches `6.3` or newer within the same
+/// series (`6.x`). `Exact` is the plain `name-version` form which requires an identical version.
+#[derive(Clone, Debug)]
+struct VersionConstraint {
+    op: ConstraintOp,
+    version: Version,
+}
+
+impl VersionConstraint {
+    fn matches(&self, version: &str) -> bool {
+        if self.op == ConstraintOp::Exact {
+            return version == self.version.original;
         }
+        let Ok(v) = version.parse::<Version>() else {
+            return false;
+        };
+        let c = &self.version;
+        match self.op {
+            ConstraintOp::Exact => unreachable!(),
+            ConstraintOp::Eq => v == *c || v.is_release_of(c),
+            ConstraintOp::Lt => v < *c && !v.is_release_of(c),
+            ConstraintOp::Le => v < *c || v.is_release_of(c),
+            ConstraintOp::Gt => v > *c && !v.is_release_of(c),
+            ConstraintOp::Ge => v >= *c,
+            ConstraintOp::Compatible => {
+                let series = Version {
+                    segments: c.segments[..c.segments.len().max(2) - 1].to_vec(),
+                    release: vec![],
+                    ..c.clone()
+                };
+                v >= *c && v.starts_with(&series)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for VersionConstraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = match self.op {
+            ConstraintOp::Exact => "-",
+            ConstraintOp::Eq => "=",
+            ConstraintOp::Lt => "<",
+            ConstraintOp::Le => "<=",
+            ConstraintOp::Gt => ">",
+            ConstraintOp::Ge => ">=",
+            ConstraintOp::Compatible => "~",
+        };
+        write!(f, "{op}{}", self.version)
+    }
+}
+
+/// Splits package spec (`name`, `name-version` or `name<op>version`) into name and version constraint.
+fn parse_package_spec(pkg_spec: &str) -> anyhow::Result<(String, Option<VersionConstraint>)> {
+    let re = lazy_regex::regex!(r"^([\w\-\.]+?)(>=|<=|==|=|>|<|~)(.*)$");
+    if let Some(caps) = re.captures(pkg_spec) {
+        let op = match &caps[2] {
+            "=" | "==" => ConstraintOp::Eq,
+            "<" => ConstraintOp::Lt,
+            "<=" => ConstraintOp::Le,
+            ">" => ConstraintOp::Gt,
+            ">=" => ConstraintOp::Ge,
+            "~" => ConstraintOp::Compatible,
+            _ => unreachable!(),
+        };
+        let version = caps[3]
+            .parse::<Version>()
+            .map_err(|err| anyhow::anyhow!("invalid version constraint in {pkg_spec}: {err}"))?;
+        return Ok((caps[1].to_string(), Some(VersionConstraint { op, version })));
+    }
+
+    let (name, version) = split_package_name_version(pkg_spec);
+    if version.is_empty() {
+        return Ok((name, None));
+    }
+    Ok((
+        name,
+        Some(VersionConstraint {
+            op: ConstraintOp::Exact,
+            version: version.parse()?,
+        }),
+    ))
+}
+
+fn format_package_spec(package_name: &str, constraints: &[VersionConstraint]) -> String {
+    format!("{package_name}{}", constraints.iter().join(","))
+}
+
+/// `Downgrade` if `version_new` is lower than `version_old`, `Update` otherwise.
+fn get_version_change_action(version_old: &str, version_new: &str) -> PlanAction {
+    match compare_versions(version_new, version_old) {
+        std::cmp::Ordering::Less => PlanAction::Downgrade,
+        _ => PlanAction::Update,
+    }
+}
+
+fn compare_versions(v1: &str, v2: &str) -> std::cmp::Ordering {
+    // invalid versions are filtered out when reading repos so this should not happen but let's not crash
+    match (v1.parse::<Version>(), v2.parse::<Version>()) {
+        (Ok(v1), Ok(v2)) => v1.cmp(&v2),
+        (Ok(_), Err(_)) => std::cmp::Ordering::Greater,
+        (Err(_), Ok(_)) => std::cmp::Ordering::Less,
+        (Err(_), Err(_)) => v1.cmp(v2),
     }
-    None
 }
 
 fn install_package(
     fn_zip: &str,
     prefix: &str,
-    force: bool,
+    overwrite: &[String],
+    adopt: bool,
     tmp_dir_prefix: &str,
+    transaction: &mut Transaction,
 ) -> anyhow::Result<InstalledPackage> {
     let (name, version_new) = split_package_name_version(&get_package_fn(fn_zip).unwrap());
     log::info!("installing {name}-{version_new}");
-    println!("installing {name}-{version_new}");
+    status!("installing {name}-{version_new}");
 
     let tmp_dir = tempfile::Builder::new().tempdir_in(tmp_dir_prefix)?;
     let tmp_dir_path = as_unix_path(tmp_dir.path());
@@ -665,79 +2619,112 @@ fn install_package(
     let (dirs, mut files) = get_recursive_listing(&tmp_dir_path)?;
     files.retain(|x| !x.starts_with(".atxpkg_"));
 
-    if !force {
-        let progress_bar = make_progress_bar(
-            files.len().try_into()?,
-            &name,
-            "{spinner} {prefix}: check [{wide_bar}] {pos}/{len}",
-        )?;
+    let progress =
+        reporter().progress(&name, "check", ProgressUnit::Items, files.len().try_into()?);
 
-        for f in progress_bar.wrap_iter(files.iter()) {
-            let target_fn = format!("{prefix}/{f}");
-            if Path::new(&target_fn).exists() {
-                anyhow::bail!("file exists: {target_fn}");
+    for f in files.iter().inspect(|_| progress.inc(1)) {
+        let target_fn = format!("{prefix}/{f}");
+        if Path::new(&target_fn).exists() {
+            if is_overwrite_allowed(f, overwrite) {
+                log::info!("overwriting untracked {target_fn}");
+            } else if !adopt {
+                anyhow::bail!(Error::Conflict(format!("file exists: {target_fn}")));
             }
         }
-
-        progress_bar.finish();
-        eprintln!();
     }
 
+    progress.finish();
+
     let mut md5sums = HashMap::with_capacity(dirs.len() + files.len());
+    let mut file_stats = HashMap::with_capacity(dirs.len() + files.len());
 
-    let progress_bar = make_progress_bar(
-        (dirs.len() + files.len()).try_into()?,
+    let progress = reporter().progress(
         &name,
-        "{spinner} {prefix}: install [{wide_bar}] {pos}/{len}",
-    )?;
+        "install",
+        ProgressUnit::Items,
+        (dirs.len() + files.len()).try_into()?,
+    );
 
-    for d in progress_bar.wrap_iter(dirs.into_iter().sorted_by_key(|x| x.len())) {
+    for d in dirs
+        .into_iter()
+        .sorted_by_key(|x| x.len())
+        .inspect(|_| progress.inc(1))
+    {
         let target_dir = format!("{prefix}/{d}");
         log::trace!("ID {d}");
-        if !Path::new(&target_dir).exists() {
-            std::fs::create_dir(&target_dir)?;
-        }
+        transaction.create_dir(&target_dir)?;
         let src_info = std::fs::metadata(format!("{tmp_dir_path}/{d}"))?;
-        std::fs::set_permissions(&target_dir, src_info.permissions())?;
         let mod_time = src_info.modified().unwrap_or(std::time::SystemTime::now());
-        filetime::set_file_times(&target_dir, mod_time.into(), mod_time.into())?;
+        transaction.set_dir_attrs(&target_dir, src_info.permissions(), mod_time)?;
+        file_stats.insert(d.clone(), FileStat::new(&target_dir)?);
         md5sums.insert(d, None);
     }
 
-    for f in progress_bar.wrap_iter(files.into_iter()) {
+    for f in files.into_iter().inspect(|_| progress.inc(1)) {
         let sum = get_md5_sum(&format!("{tmp_dir_path}/{f}"))?;
-        md5sums.insert(f.clone(), Some(sum));
+        md5sums.insert(f.clone(), Some(sum.clone()));
 
         let target_fn = format!("{prefix}/{f}");
+        if adopt && Path::new(&target_fn).exists() && !is_overwrite_allowed(&f, overwrite) {
+            let sum_current = get_md5_sum(&target_fn)?;
+            if sum_current == sum {
+                log::trace!("AF {target_fn}");
+                file_stats.insert(f, FileStat::new(&target_fn)?);
+            } else if backup.clone().unwrap_or_default().contains(&f) {
+                // keep the site-specific version in place, same as update_package does for altered backup files
+                log::info!(
+                    "adopting changed {target_fn}, installing new version as {target_fn}.atxpkg_new"
+                );
+                progress.println(&format!(
+                    "adopting changed {target_fn}, installing new version as {target_fn}.atxpkg_new"
+                ));
+                transaction.put_file(
+                    &format!("{tmp_dir_path}/{f}"),
+                    &format!("{target_fn}.atxpkg_new"),
+                )?;
+            } else {
+                log::warn!("adopting {target_fn} with checksum difference");
+                progress.println(&format!("adopting {target_fn} with checksum difference"));
+            }
+            continue;
+        }
         if Path::new(&target_fn).exists() && backup.clone().unwrap_or_default().contains(&f) {
             log::info!("saving untracked {target_fn} as {target_fn}.atxpkg_save");
             //progress_bar.println(format!(
             //    "saving untracked {target_fn} as {target_fn}.atxpkg_save"
             //));
-            progress_bar.suspend(|| {
-                eprintln!("saving untracked {target_fn} as {target_fn}.atxpkg_save");
-            });
-            move_file(&target_fn, &format!("{target_fn}.atxpkg_save"))?;
+            progress.println(&format!(
+                "saving untracked {target_fn} as {target_fn}.atxpkg_save"
+            ));
+            transaction.move_file(&target_fn, &format!("{target_fn}.atxpkg_save"))?;
         }
         log::trace!("IF {target_fn}");
-        move_file(&format!("{tmp_dir_path}/{f}"), &target_fn)?;
+        transaction.put_file(&format!("{tmp_dir_path}/{f}"), &target_fn)?;
+        file_stats.insert(f, FileStat::new(&target_fn)?);
     }
 
-    progress_bar.finish();
-    eprintln!();
+    progress.finish();
 
     Ok(InstalledPackage {
         t: Some(UNIX_EPOCH.elapsed()?.as_secs_f64()),
         version: version_new.clone(),
         md5sums,
         backup,
+        repo: None,
+        hold: None,
+        channel: None,
+        previous: None,
+        file_stats: Some(file_stats),
     })
 }
 
 fn get_md5_sum(file_path: &str) -> anyhow::Result<String> {
+    get_md5_sum_from_reader(BufReader::new(File::open(file_path)?))
+}
+
+fn get_md5_sum_from_reader(mut reader: impl Read) -> anyhow::Result<String> {
     let mut hasher = Md5::new();
-    let mut buffer = [0u8; MD5_BUFFER_SIZE];
-    let mut reader = BufReader::new(File::open(file_path)?);
+    let mut buffer = vec![0u8; MD5_BUFFER_SIZE];
     while let Ok(size) = reader.read(&mut buffer) {
         hasher.update(&buffer[..size]);
         if size == 0 {
@@ -747,22 +2734,20 @@ fn get_md5_sum(file_path: &str) -> anyhow::Result<String> {
     Ok(hex::encode(hasher.finalize()))
 }
 
-fn unzip_to(
-    zip_file_path: &str,
-    output_dir: &str,
-    progress_bar_prefix: &str,
-) -> anyhow::Result<()> {
+#[allow(clippy::collapsible_if)]
+fn unzip_to(zip_file_path: &str, output_dir: &str, progress_prefix: &str) -> anyhow::Result<()> {
     log::debug!("unzip {zip_file_path} to {output_dir}");
 
     let mut archive = zip::read::ZipArchive::new(BufReader::new(File::open(zip_file_path)?))?;
 
-    let progress_bar = make_progress_bar(
+    let progress = reporter().progress(
+        progress_prefix,
+        "unzip",
+        ProgressUnit::Items,
         archive.len().try_into()?,
-        progress_bar_prefix,
-        "{spinner} {prefix}: unzip [{wide_bar}] {pos}/{len}",
-    )?;
+    );
 
-    for i in progress_bar.wrap_iter(0..archive.len()) {
+    for i in (0..archive.len()).inspect(|_| progress.inc(1)) {
         let mut file = archive.by_index(i)?;
         let outpath = Path::new(&output_dir).join(file.name());
         log::trace!("unzip {}", as_unix_path(&outpath));
@@ -803,13 +2788,70 @@ fn unzip_to(
         };
     }
 
-    progress_bar.finish();
-    eprintln!();
+    progress.finish();
 
     log::debug!("done unzipping");
     Ok(())
 }
 
+fn get_package_listing(zip_file_path: &str) -> anyhow::Result<PackageListing> {
+    log::debug!("listing {zip_file_path}");
+
+    let mut archive = zip::read::ZipArchive::new(BufReader::new(File::open(zip_file_path)?))?;
+
+    let mut dirs = HashSet::new();
+    let mut files = Vec::with_capacity(archive.len());
+    let mut backup = None;
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let name = file.name().trim_end_matches('/').to_string();
+        if name == ".atxpkg_backup" {
+            let mut content = String::new();
+            file.read_to_string(&mut content)?;
+            backup = Some(parse_lines(&content));
+            continue;
+        }
+        if name.is_empty() || name.starts_with(".atxpkg_") {
+            continue;
+        }
+        // parent directories are not necessarily stored in the archive
+        for p in Path::new(&name).ancestors().skip(1) {
+            let p = as_unix_path(p);
+            if !p.is_empty() {
+                dirs.insert(p);
+            }
+        }
+        if file.is_dir() {
+            dirs.insert(name);
+        } else {
+            files.push((name, file.size()));
+        }
+    }
+
+    Ok(PackageListing {
+        dirs: dirs.into_iter().sorted().collect(),
+        files: files.into_iter().sorted().collect(),
+        backup,
+    })
+}
+
+fn get_package_md5sums(zip_file_path: &str) -> anyhow::Result<HashMap<String, String>> {
+    let mut archive = zip::read::ZipArchive::new(BufReader::new(File::open(zip_file_path)?))?;
+
+    let mut ret = HashMap::with_capacity(archive.len());
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        if file.is_dir() || file.name().starts_with(".atxpkg_") {
+            continue;
+        }
+        let name = file.name().to_string();
+        ret.insert(name, get_md5_sum_from_reader(file)?);
+    }
+
+    Ok(ret)
+}
+
 fn get_recursive_listing(path_base: &str) -> anyhow::Result<(Vec<String>, Vec<String>)> {
     // Pre-allocate with reasonable initial capacity
     let (mut ret_dirs, mut ret_files) = (Vec::with_capacity(32), Vec::with_capacity(128));
@@ -834,12 +2876,16 @@ fn get_recursive_listing(path_base: &str) -> anyhow::Result<(Vec<String>, Vec<St
     Ok((ret_dirs, ret_files))
 }
 
-fn read_lines(fn_: &str) -> anyhow::Result<Vec<String>> {
-    Ok(std::fs::read_to_string(fn_)?
-        .split('\n')
+/// Reads non-empty lines of a file, `#` comments are skipped.
+pub fn read_lines(fn_: &str) -> anyhow::Result<Vec<String>> {
+    Ok(parse_lines(&std::fs::read_to_string(fn_)?))
+}
+
+fn parse_lines(s: &str) -> Vec<String> {
+    s.split('\n')
         .map(|s| s.trim().to_string())
         .filter(|s| !s.is_empty() && !s.starts_with('#'))
-        .collect::<Vec<_>>())
+        .collect::<Vec<_>>()
 }
 
 pub fn update_package(
@@ -847,13 +2893,44 @@ pub fn update_package(
     name_old: &str,
     installed_package: InstalledPackage,
     prefix: &str,
-    force: bool,
+    overwrite: &[String],
+    tmp_dir_prefix: &str,
+) -> anyhow::Result<InstalledPackage> {
+    let mut transaction = Transaction::new(tmp_dir_prefix, None)?;
+    match update_package_in_transaction(
+        fn_zip,
+        name_old,
+        installed_package,
+        prefix,
+        overwrite,
+        tmp_dir_prefix,
+        &mut transaction,
+    ) {
+        Ok(package_info) => {
+            transaction.commit(None)?;
+            Ok(package_info)
+        }
+        Err(err) => {
+            transaction.rollback()?;
+            Err(err)
+        }
+    }
+}
+
+#[allow(clippy::collapsible_if)]
+fn update_package_in_transaction(
+    fn_zip: &str,
+    name_old: &str,
+    installed_package: InstalledPackage,
+    prefix: &str,
+    overwrite: &[String],
     tmp_dir_prefix: &str,
+    transaction: &mut Transaction,
 ) -> anyhow::Result<InstalledPackage> {
     let version_old = installed_package.version.clone();
     let (name, version_new) = split_package_name_version(&get_package_fn(fn_zip).unwrap());
     log::info!("updating {name_old}-{version_old} -> {name}-{version_new}");
-    println!("updating {name_old}-{version_old} -> {name}-{version_new}");
+    status!("updating {name_old}-{version_old} -> {name}-{version_new}");
 
     let tmp_dir = tempfile::Builder::new().tempdir_in(tmp_dir_prefix)?;
     let tmp_dir_path = as_unix_path(tmp_dir.path());
@@ -864,46 +2941,45 @@ pub fn update_package(
     let (dirs, mut files) = get_recursive_listing(&tmp_dir_path)?;
     files.retain(|x| !x.starts_with(".atxpkg_"));
 
-    if !force {
-        let progress_bar = make_progress_bar(
-            files.len().try_into()?,
-            &name,
-            "{spinner} {prefix}: check [{wide_bar}] {pos}/{len}",
-        )?;
+    let progress =
+        reporter().progress(&name, "check", ProgressUnit::Items, files.len().try_into()?);
 
-        for f in &files {
-            let target_fn = format!("{prefix}/{f}");
-            if Path::new(&target_fn).exists() && !installed_package.md5sums.contains_key(f) {
-                anyhow::bail!("{f} already exists but is not part of original package");
+    for f in files.iter().inspect(|_| progress.inc(1)) {
+        let target_fn = format!("{prefix}/{f}");
+        if Path::new(&target_fn).exists() && !installed_package.md5sums.contains_key(f) {
+            if !is_overwrite_allowed(f, overwrite) {
+                anyhow::bail!(Error::Conflict(format!(
+                    "{f} already exists but is not part of original package"
+                )));
             }
+            log::info!("overwriting untracked {target_fn}");
         }
-
-        progress_bar.finish();
-        eprintln!();
     }
 
+    progress.finish();
+
     let mut md5sums = HashMap::with_capacity(dirs.len() + files.len());
+    let mut file_stats = HashMap::with_capacity(dirs.len() + files.len());
 
-    let progress_bar = make_progress_bar(
-        (dirs.len() + files.len()).try_into()?,
+    let progress = reporter().progress(
         &name,
-        "{spinner} {prefix}: update [{wide_bar}] {pos}/{len}",
-    )?;
+        "update",
+        ProgressUnit::Items,
+        (dirs.len() + files.len()).try_into()?,
+    );
 
-    for d in progress_bar.wrap_iter(dirs.into_iter().sorted()) {
+    for d in dirs.into_iter().sorted().inspect(|_| progress.inc(1)) {
         let target_dir = format!("{prefix}/{d}");
         log::trace!("UD {target_dir}");
-        if !Path::new(&target_dir).exists() {
-            std::fs::create_dir(&target_dir)?;
-        }
+        transaction.create_dir(&target_dir)?;
         let src_info = std::fs::metadata(format!("{tmp_dir_path}/{d}"))?;
-        std::fs::set_permissions(&target_dir, src_info.permissions())?;
         let mod_time = src_info.modified().unwrap_or(std::time::SystemTime::now());
-        filetime::set_file_times(&target_dir, mod_time.into(), mod_time.into())?;
+        transaction.set_dir_attrs(&target_dir, src_info.permissions(), mod_time)?;
+        file_stats.insert(d.clone(), FileStat::new(&target_dir)?);
         md5sums.insert(d, None);
     }
 
-    for f in progress_bar.wrap_iter(files.into_iter()) {
+    for f in files.into_iter().inspect(|_| progress.inc(1)) {
         let sum_new = get_md5_sum(&format!("{tmp_dir_path}/{f}"))?;
         md5sums.insert(f.clone(), Some(sum_new.clone()));
 
@@ -920,9 +2996,9 @@ pub fn update_package(
                     //progress_bar.println(format!(
                     //    "sum for file {target_fn} changed, installing new version as {target_fn}.atxpkg_new"
                     //));
-                    progress_bar.suspend(|| {
-                        eprintln!("sum for file {target_fn} changed, installing new version as {target_fn}.atxpkg_new");
-                    });
+                    progress.println(&format!(
+                        "sum for file {target_fn} changed, installing new version as {target_fn}.atxpkg_new"
+                    ));
                     target_fn += ".atxpkg_new";
                 }
                 /*
@@ -968,11 +3044,13 @@ pub fn update_package(
             }
         }
         log::trace!("UF {target_fn}");
-        move_file(&format!("{tmp_dir_path}/{f}"), &target_fn)?;
+        transaction.put_file(&format!("{tmp_dir_path}/{f}"), &target_fn)?;
+        if !target_fn.ends_with(".atxpkg_new") {
+            file_stats.insert(f, FileStat::new(&target_fn)?);
+        }
     }
 
-    progress_bar.finish();
-    eprintln!();
+    progress.finish();
 
     let (mut dirs_old, mut files_old) = (vec![], vec![]);
     for (fn_or_dir_old, md5sum_old) in installed_package.md5sums.into_iter() {
@@ -983,13 +3061,14 @@ pub fn update_package(
         }
     }
 
-    let progress_bar = make_progress_bar(
-        (dirs_old.len() + files_old.len()).try_into()?,
+    let progress = reporter().progress(
         &name,
-        "{spinner} {prefix}: cleanup [{wide_bar}] {pos}/{len}",
-    )?;
+        "cleanup",
+        ProgressUnit::Items,
+        (dirs_old.len() + files_old.len()).try_into()?,
+    );
 
-    for (fn_old, md5sum_old) in progress_bar.wrap_iter(files_old.into_iter()) {
+    for (fn_old, md5sum_old) in files_old.into_iter().inspect(|_| progress.inc(1)) {
         if md5sums.contains_key(&fn_old) {
             continue;
         }
@@ -997,7 +3076,7 @@ pub fn update_package(
         if !Path::new(&target_fn).exists() {
             log::warn!("file {target_fn} does not exist");
             //progress_bar.println(format!("file {target_fn} does not exist!"));
-            progress_bar.suspend(|| eprintln!("file {target_fn} does not exist!"));
+            progress.println(&format!("file {target_fn} does not exist!"));
             continue;
         }
         if installed_package
@@ -1013,21 +3092,26 @@ pub fn update_package(
                 //progress_bar.println(format!(
                 //    "saving changed {target_fn} as {target_fn}.atxpkg_save"
                 //));
-                progress_bar.suspend(|| {
-                    eprintln!("saving changed {target_fn} as {target_fn}.atxpkg_save");
-                });
-                move_file(&target_fn, &format!("{target_fn}.atxpkg_save"))?;
+                progress.println(&format!(
+                    "saving changed {target_fn} as {target_fn}.atxpkg_save"
+                ));
+                transaction.move_file(&target_fn, &format!("{target_fn}.atxpkg_save"))?;
             } else {
                 log::trace!("DF {target_fn}");
-                try_delete(&target_fn)?;
+                transaction.delete(&target_fn)?;
             }
         } else {
             log::trace!("DF {target_fn}");
-            try_delete(&target_fn)?;
+            transaction.delete(&target_fn)?;
         }
     }
 
-    for dir_name in progress_bar.wrap_iter(dirs_old.into_iter().sorted_by_key(|x| x.len()).rev()) {
+    for dir_name in dirs_old
+        .into_iter()
+        .sorted_by_key(|x| x.len())
+        .rev()
+        .inspect(|_| progress.inc(1))
+    {
         if md5sums.contains_key(&dir_name) {
             continue;
         }
@@ -1036,63 +3120,209 @@ pub fn update_package(
         if !Path::new(&target_fn).exists() {
             log::warn!("dir {target_fn} does not exist");
             //progress_bar.println(format!("{target_fn} does not exist!"));
-            progress_bar.suspend(|| eprintln!("{target_fn} does not exist!"));
+            progress.println(&format!("{target_fn} does not exist!"));
+            continue;
+        }
+
+        let dir_path = Path::new(&target_fn);
+        if dir_path != Path::new(&prefix) && is_empty_dir(dir_path)? {
+            log::trace!("DD {target_fn}");
+            transaction.remove_dir(&target_fn)?;
+        }
+    }
+
+    progress.finish();
+
+    Ok(InstalledPackage {
+        t: Some(UNIX_EPOCH.elapsed()?.as_secs_f64()),
+        version: version_new.clone(),
+        md5sums,
+        backup,
+        repo: None,
+        hold: installed_package.hold.clone(),
+        channel: installed_package.channel,
+        previous: installed_package.previous.clone(),
+        file_stats: Some(file_stats),
+    })
+}
+
+/// Whether `update_package` installs the new version of backup file `f` as `.atxpkg_new`.
+fn is_changed_backup_file(
+    f: &str,
+    target_fn: &str,
+    backup: &[String],
+    installed_package: &InstalledPackage,
+    sum_new: &str,
+) -> anyhow::Result<bool> {
+    if !Path::new(target_fn).exists() || !backup.iter().any(|x| x == f) {
+        return Ok(false);
+    }
+    let Some(Some(sum_original)) = installed_package.md5sums.get(f) else {
+        return Ok(false);
+    };
+    let sum_current = get_md5_sum(target_fn)?;
+    // only if the user has altered the file and it's altered in a way that it is not the same as the to-be-installed version - only then install the new file to different location
+    Ok(*sum_original != sum_current && sum_current != sum_new)
+}
+
+pub fn preview_update_package(
+    fn_zip: &str,
+    installed_package: &InstalledPackage,
+    prefix: &str,
+    overwrite: &[String],
+) -> anyhow::Result<Vec<(String, FileAction)>> {
+    let md5sums = get_package_md5sums(fn_zip)?;
+    let backup = get_package_listing(fn_zip)?.backup.unwrap_or_default();
+    let backup_old = installed_package.backup.clone().unwrap_or_default();
+
+    let mut ret = Vec::with_capacity(md5sums.len());
+
+    for (f, sum_new) in md5sums.iter().sorted() {
+        let target_fn = format!("{prefix}/{f}");
+        let exists = Path::new(&target_fn).exists();
+        let action = match installed_package.md5sums.get(f) {
+            None if exists && is_overwrite_allowed(f, overwrite) => FileAction::Overwrite,
+            None if exists => FileAction::Conflict,
+            None => FileAction::Add,
+            Some(_)
+                if is_changed_backup_file(f, &target_fn, &backup, installed_package, sum_new)? =>
+            {
+                FileAction::New
+            }
+            Some(Some(sum_original))
+                if sum_original == sum_new && exists && get_md5_sum(&target_fn)? == *sum_new =>
+            {
+                FileAction::Keep
+            }
+            Some(_) => FileAction::Replace,
+        };
+        ret.push((f.clone(), action));
+    }
+
+    for (fn_old, md5sum_old) in installed_package.md5sums.iter().sorted() {
+        let Some(md5sum_old) = md5sum_old else {
+            continue;
+        };
+        if md5sums.contains_key(fn_old) {
             continue;
         }
+        let target_fn = format!("{prefix}/{fn_old}");
+        let action = if !Path::new(&target_fn).exists() {
+            FileAction::Missing
+        } else if backup_old.contains(fn_old) && get_md5_sum(&target_fn)? != *md5sum_old {
+            FileAction::Save
+        } else {
+            FileAction::Remove
+        };
+        ret.push((fn_old.clone(), action));
+    }
+
+    Ok(ret)
+}
+
+pub fn preview_update_packages(
+    packages: Vec<String>,
+    installed_packages: &HashMap<String, InstalledPackage>,
+    prefix: &str,
+    repos: Vec<String>,
+    channel: Option<Channel>,
+    options: &Options,
+    cache_dir: &str,
+) -> anyhow::Result<Vec<UpdatePreview>> {
+    check_overwrite_globs(&options.overwrite)?;
+
+    let package_updates = get_package_updates(
+        packages,
+        installed_packages,
+        repos,
+        channel,
+        &Options {
+            reinstall: false,
+            ..options.clone()
+        },
+        cache_dir,
+    )?;
 
-        let dir_path = Path::new(&target_fn);
-        if dir_path != Path::new(&prefix) && is_empty_dir(dir_path)? {
-            log::trace!("DD {target_fn}");
-            std::fs::remove_dir(dir_path)?;
-        }
+    let mut ret = Vec::with_capacity(package_updates.len());
+    for pu in package_updates {
+        let local_fn =
+            download_package_if_needed(&pu.url, cache_dir, options.unverified_ssl, None)?;
+        let files = preview_update_package(
+            &local_fn,
+            &installed_packages[&pu.name_old],
+            prefix,
+            &options.overwrite,
+        )?;
+        ret.push(UpdatePreview {
+            name_old: pu.name_old,
+            version_old: pu.version_old,
+            name_new: pu.name_new,
+            version_new: pu.version_new,
+            files,
+        });
     }
 
-    progress_bar.finish();
-    eprintln!();
-
-    Ok(InstalledPackage {
-        t: Some(UNIX_EPOCH.elapsed()?.as_secs_f64()),
-        version: version_new.clone(),
-        md5sums,
-        backup,
-    })
+    Ok(ret)
 }
 
 pub fn remove_packages(
     packages: Vec<String>,
     installed_packages: &mut HashMap<String, InstalledPackage>,
     prefix: &str,
-    yes: bool,
-    no: bool,
+    options: &Options,
+    tmp_dir_prefix: &str,
+    history_fn: Option<&str>,
 ) -> anyhow::Result<bool> {
+    let mut plan = Vec::with_capacity(packages.len());
     for p in &packages {
         let (package_name, mut package_version) = split_package_name_version(p);
         let Some(installed_package) = installed_packages.get(&package_name) else {
-            anyhow::bail!("package {package_name} not installed");
+            anyhow::bail!(Error::NotInstalled(format!(
+                "package {package_name} not installed"
+            )));
         };
         if !package_version.is_empty() {
             if package_version != installed_package.version {
-                anyhow::bail!("package {package_name}-{package_version} not installed");
+                anyhow::bail!(Error::NotInstalled(format!(
+                    "package {package_name}-{package_version} not installed"
+                )));
             }
         } else {
             package_version.clone_from(&installed_package.version);
         }
 
-        println!("remove {package_name}-{package_version}");
+        plan.push(PlannedChange {
+            action: PlanAction::Remove,
+            name: package_name,
+            version: package_version,
+            name_old: None,
+            version_old: None,
+            detail: None,
+        });
     }
-    if no || !(yes || yes_no("continue?", "n")?) {
+    reporter().plan(&plan);
+    if !confirm(options.yes, options.no, false)? {
         return Ok(false);
     }
 
-    for p in &packages {
-        let package_name = get_package_name(p);
-        remove_package(
-            &package_name,
-            installed_packages[&package_name].clone(),
-            prefix,
-        )?;
-        installed_packages.remove(&package_name);
-    }
+    with_transaction(
+        installed_packages,
+        tmp_dir_prefix,
+        history_fn,
+        |installed_packages, transaction| {
+            for p in &packages {
+                let package_name = get_package_name(p);
+                remove_package_in_transaction(
+                    &package_name,
+                    installed_packages[&package_name].clone(),
+                    prefix,
+                    transaction,
+                )?;
+                installed_packages.remove(&package_name);
+            }
+            Ok(())
+        },
+    )?;
 
     Ok(true)
 }
@@ -1101,6 +3331,23 @@ pub fn remove_package(
     package_name: &str,
     installed_package: InstalledPackage,
     prefix: &str,
+    tmp_dir_prefix: &str,
+) -> anyhow::Result<()> {
+    let mut transaction = Transaction::new(tmp_dir_prefix, None)?;
+    match remove_package_in_transaction(package_name, installed_package, prefix, &mut transaction) {
+        Ok(()) => transaction.commit(None),
+        Err(err) => {
+            transaction.rollback()?;
+            Err(err)
+        }
+    }
+}
+
+fn remove_package_in_transaction(
+    package_name: &str,
+    installed_package: InstalledPackage,
+    prefix: &str,
+    transaction: &mut Transaction,
 ) -> anyhow::Result<()> {
     let version = &installed_package.version;
     log::info!("removing {package_name}-{version}");
@@ -1114,18 +3361,19 @@ pub fn remove_package(
         }
     }
 
-    let progress_bar = make_progress_bar(
-        (dirs.len() + files.len()).try_into()?,
+    let progress = reporter().progress(
         package_name,
-        "{spinner} {prefix}: remove [{wide_bar}] {pos}/{len}",
-    )?;
+        "remove",
+        ProgressUnit::Items,
+        (dirs.len() + files.len()).try_into()?,
+    );
 
-    for (file_name, md5sum) in progress_bar.wrap_iter(files.into_iter()) {
+    for (file_name, md5sum) in files.into_iter().inspect(|_| progress.inc(1)) {
         let target_fn = format!("{prefix}/{file_name}");
         if !Path::new(&target_fn).exists() {
             log::warn!("file {target_fn} does not exist!");
             //progress_bar.println(format!("{target_fn} does not exist!"));
-            progress_bar.suspend(|| eprintln!("{target_fn} does not exist!"));
+            progress.println(&format!("{target_fn} does not exist!"));
             continue;
         }
 
@@ -1141,38 +3389,42 @@ pub fn remove_package(
                 //progress_bar.println(format!(
                 //    "{target_fn} changed, saving as {target_fn}.atxpkg_backup"
                 //));
-                progress_bar.suspend(|| {
-                    eprintln!("{target_fn} changed, saving as {target_fn}.atxpkg_backup");
-                });
-                move_file(&target_fn, &format!("{target_fn}.atxpkg_backup"))?;
+                progress.println(&format!(
+                    "{target_fn} changed, saving as {target_fn}.atxpkg_backup"
+                ));
+                transaction.move_file(&target_fn, &format!("{target_fn}.atxpkg_backup"))?;
             } else {
                 log::trace!("DF {target_fn}");
-                try_delete(&target_fn)?;
+                transaction.delete(&target_fn)?;
             }
         } else {
             log::trace!("DF {target_fn}");
-            try_delete(&target_fn)?;
+            transaction.delete(&target_fn)?;
         }
     }
 
-    for dir_name in progress_bar.wrap_iter(dirs.into_iter().sorted_by_key(|x| x.len()).rev()) {
+    for dir_name in dirs
+        .into_iter()
+        .sorted_by_key(|x| x.len())
+        .rev()
+        .inspect(|_| progress.inc(1))
+    {
         let target_fn = format!("{prefix}/{dir_name}");
         if !Path::new(&target_fn).exists() {
             log::warn!("dir {target_fn} does not exist!");
             //progress_bar.println(format!("{target_fn} does not exist!"));
-            progress_bar.suspend(|| eprintln!("{target_fn} does not exist!"));
+            progress.println(&format!("{target_fn} does not exist!"));
             continue;
         }
 
         let dir_path = Path::new(&target_fn);
         if dir_path != Path::new(&prefix) && is_empty_dir(dir_path)? {
             log::trace!("DD {target_fn}");
-            std::fs::remove_dir(dir_path)?;
+            transaction.remove_dir(&target_fn)?;
         }
     }
 
-    progress_bar.finish();
-    eprintln!();
+    progress.finish();
 
     Ok(())
 }
@@ -1181,20 +3433,23 @@ pub fn is_empty_dir(path: &Path) -> anyhow::Result<bool> {
     Ok(path.read_dir()?.next().is_none())
 }
 
-pub fn update_packages(
+fn get_package_updates(
     packages: Vec<String>,
-    installed_packages: &mut HashMap<String, InstalledPackage>,
-    prefix: &str,
+    installed_packages: &HashMap<String, InstalledPackage>,
     repos: Vec<String>,
-    force: bool,
-    offline: bool,
-    yes: bool,
-    no: bool,
-    download_only: bool,
-    unverified_ssl: bool,
+    channel: Option<Channel>,
+    options: &Options,
     cache_dir: &str,
-    tmp_dir_prefix: &str,
-) -> anyhow::Result<bool> {
+) -> anyhow::Result<Vec<PackageUpdate>> {
+    let packages = match packages.is_empty() {
+        // holds may only be ignored for explicitly named packages
+        true if options.ignore_hold => {
+            anyhow::bail!("--ignore-hold requires explicitly named packages")
+        }
+        true => installed_packages.keys().cloned().sorted().collect(),
+        false => packages,
+    };
+
     let mut package_updates = vec![];
 
     for p in &packages {
@@ -1202,104 +3457,297 @@ pub fn update_packages(
             let package_parts = p.split("..").collect::<Vec<_>>();
             let (package_old, package_new) = (package_parts[0], package_parts[1]);
             let (package_name_old, package_version_old) = split_package_name_version(package_old);
-            let (package_name_new, package_version_new) = split_package_name_version(package_new);
-            PackageUpdate {
-                name_old: package_name_old,
-                version_old: package_version_old,
-                name_new: package_name_new,
-                version_new: package_version_new,
-                url: String::new(),
-                local_fn: String::new(),
-            }
+            let (package_name_new, constraint_new) = parse_package_spec(package_new)?;
+            (
+                PackageUpdate {
+                    name_old: package_name_old,
+                    version_old: package_version_old,
+                    name_new: package_name_new,
+                    version_new: String::new(),
+                    url: String::new(),
+                    local_fn: String::new(),
+                    repo: None,
+                    url_old: None,
+                    local_fn_old: None,
+                },
+                constraint_new,
+                false,
+            )
         } else {
-            let (name, version) = split_package_name_version(p);
-            PackageUpdate {
-                name_old: name.clone(),
-                version_old: String::new(),
-                name_new: name,
-                version_new: version,
-                url: String::new(),
-                local_fn: String::new(),
-            }
+            let (name, constraint) = parse_package_spec(p)?;
+            let follow_channel = constraint.is_none();
+            (
+                PackageUpdate {
+                    name_old: name.clone(),
+                    version_old: String::new(),
+                    name_new: name,
+                    version_new: String::new(),
+                    url: String::new(),
+                    local_fn: String::new(),
+                    repo: None,
+                    url_old: None,
+                    local_fn_old: None,
+                },
+                constraint,
+                follow_channel,
+            )
         };
         package_updates.push(pu);
     }
 
-    for pu in &mut package_updates {
+    for (pu, _, _) in &mut package_updates {
         let Some(installed_package) = installed_packages.get(&pu.name_old) else {
-            anyhow::bail!("package {} not installed", pu.name_old);
+            anyhow::bail!(Error::NotInstalled(format!(
+                "package {} not installed",
+                pu.name_old
+            )));
         };
         if pu.version_old.is_empty() {
             pu.version_old.clone_from(&installed_package.version);
         } else if pu.version_old != installed_package.version {
-            anyhow::bail!("package {}-{} not installed", pu.name_old, pu.version_old);
+            anyhow::bail!(Error::NotInstalled(format!(
+                "package {}-{} not installed",
+                pu.name_old, pu.version_old
+            )));
         }
         if pu.name_old != pu.name_new && installed_packages.contains_key(&pu.name_new) {
-            anyhow::bail!("package {} already installed", pu.name_new);
+            anyhow::bail!(Error::Conflict(format!(
+                "package {} already installed",
+                pu.name_new
+            )));
         }
     }
 
-    let available_packages = get_available_packages(repos, offline, unverified_ssl)?;
-
-    for pu in &mut package_updates {
-        let Some(avail_pkg) = available_packages.get(&pu.name_new) else {
-            anyhow::bail!("package {} not available", pu.name_new);
-        };
-        if pu.version_new.is_empty() {
-            pu.version_new = get_max_version(avail_pkg.clone()).unwrap();
+    let available_packages =
+        get_available_packages(repos, options.offline, options.unverified_ssl)?;
+
+    let mut ret = Vec::with_capacity(package_updates.len());
+    for (mut pu, constraint, follow_channel) in package_updates {
+        if follow_channel {
+            let package_channel = installed_packages[&pu.name_old].channel.or(channel);
+            pu.name_new =
+                get_channel_package_name(&pu.name_old, package_channel, &available_packages);
+            if pu.name_old != pu.name_new && installed_packages.contains_key(&pu.name_new) {
+                anyhow::bail!(Error::Conflict(format!(
+                    "package {} already installed",
+                    pu.name_new
+                )));
+            }
         }
-        let url = get_specific_version_url(avail_pkg.clone(), &pu.version_new).unwrap();
-        if url.is_empty() {
-            anyhow::bail!("package {}-{} not available", pu.name_new, pu.version_new);
+        let mut constraints = constraint.into_iter().collect::<Vec<_>>();
+        pu.url = match &installed_packages[&pu.name_old].hold {
+            Some(hold) if !options.ignore_hold => {
+                let held_url = match &hold.max_version {
+                    Some(max_version) => {
+                        constraints.push(VersionConstraint {
+                            op: ConstraintOp::Le,
+                            version: max_version.parse()?,
+                        });
+                        get_package_url(&pu.name_new, &constraints, &available_packages).ok()
+                    }
+                    None => None,
+                };
+                // a hold never moves the package backwards
+                let Some(url) = held_url.filter(|url| {
+                    compare_versions(
+                        &get_package_version(&get_package_fn(url).unwrap()),
+                        &pu.version_old,
+                    ) == std::cmp::Ordering::Greater
+                }) else {
+                    status!(
+                        "skip {}-{} (held{})",
+                        pu.name_old,
+                        pu.version_old,
+                        hold.max_version
+                            .as_ref()
+                            .map(|x| format!(" at <={x}"))
+                            .unwrap_or_default()
+                    );
+                    continue;
+                };
+                url
+            }
+            _ => get_package_url(&pu.name_new, &constraints, &available_packages)?,
+        };
+        pu.version_new = get_package_version(&get_package_fn(&pu.url).unwrap());
+        pu.repo = get_origin_repo(&pu.url, &available_packages, cache_dir);
+        // the old package file is kept for rollback so it does not matter if it disappears from repos
+        let rollback_fn = get_rollback_fn(cache_dir, &pu.name_old, &pu.version_old);
+        pu.url_old = match Path::new(&rollback_fn).exists() {
+            true => Some(rollback_fn),
+            false => {
+                let constraint_old =
+                    parse_package_spec(&format!("{}-{}", pu.name_old, pu.version_old))?.1;
+                get_package_url(&pu.name_old, constraint_old.as_slice(), &available_packages).ok()
+            }
+        };
+        if options.reinstall || pu.name_old != pu.name_new || pu.version_old != pu.version_new {
+            ret.push(pu);
         }
-        pu.url = url;
     }
 
-    package_updates
-        .retain(|pu| force || pu.name_old != pu.name_new || pu.version_old != pu.version_new);
+    Ok(ret)
+}
 
-    if package_updates.is_empty() {
-        println!("nothing to update");
-        return Ok(false);
+fn get_rollback_dir(cache_dir: &str) -> String {
+    format!("{cache_dir}/rollback")
+}
+
+fn get_rollback_fn(cache_dir: &str, package_name: &str, package_version: &str) -> String {
+    format!(
+        "{}/{package_name}-{package_version}.atxpkg.zip",
+        get_rollback_dir(cache_dir)
+    )
+}
+
+/// Copies the replaced package file to rollback dir (dropping the one of the older rollback).
+fn keep_for_rollback(pu: &PackageUpdate, previous: Option<&PreviousPackage>, cache_dir: &str) {
+    if pu.name_old == pu.name_new && pu.version_old == pu.version_new {
+        return;
     }
 
-    for pu in &package_updates {
-        match download_only {
-            true => println!(
-                "download {}-{} -> {}-{}",
-                pu.name_old, pu.version_old, pu.name_new, pu.version_new
-            ),
-            false => println!(
-                "update {}-{} -> {}-{}",
-                pu.name_old, pu.version_old, pu.name_new, pu.version_new
-            ),
+    let rollback_fn = get_rollback_fn(cache_dir, &pu.name_old, &pu.version_old);
+    let res = (|| {
+        let Some(local_fn_old) = &pu.local_fn_old else {
+            log::info!(
+                "{}-{} not cached, not kept for rollback",
+                pu.name_old,
+                pu.version_old
+            );
+            return anyhow::Ok(());
+        };
+        std::fs::create_dir_all(get_rollback_dir(cache_dir))?;
+        if *local_fn_old != rollback_fn {
+            std::fs::copy(local_fn_old, &rollback_fn)?;
         }
+        anyhow::Ok(())
+    })();
+    if let Err(err) = res {
+        log::warn!(
+            "unable to keep {}-{} for rollback: {err}",
+            pu.name_old,
+            pu.version_old
+        );
+        reporter().warning(&format!(
+            "warning: unable to keep {}-{} for rollback: {err}",
+            pu.name_old, pu.version_old
+        ));
     }
-    if no || !(yes || yes_no("continue?", "y")?) {
-        return Ok(false);
+
+    if let Some(previous) = previous {
+        let previous_fn = get_rollback_fn(cache_dir, &previous.name, &previous.version);
+        if previous_fn != rollback_fn && Path::new(&previous_fn).exists() {
+            log::debug!("deleting {previous_fn}");
+            if let Err(err) = std::fs::remove_file(&previous_fn) {
+                log::warn!("failed to delete {previous_fn}: {err}");
+            }
+        }
     }
+}
+
+/// Updates `packages`, all installed ones if empty (`ignore_hold` needs them named explicitly).
+#[allow(clippy::too_many_arguments)]
+pub fn update_packages(
+    packages: Vec<String>,
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+    prefix: &str,
+    repos: Vec<String>,
+    channel: Option<Channel>,
+    options: &Options,
+    cache_dir: &str,
+    tmp_dir_prefix: &str,
+    history_fn: Option<&str>,
+) -> anyhow::Result<bool> {
+    with_transaction(
+        installed_packages,
+        tmp_dir_prefix,
+        history_fn,
+        |installed_packages, transaction| {
+            check_overwrite_globs(&options.overwrite)?;
+            let package_updates = get_package_updates(
+                packages,
+                installed_packages,
+                repos,
+                channel,
+                options,
+                cache_dir,
+            )?;
+            reporter().plan(&get_update_plan(&package_updates, options.download_only));
+            if package_updates.is_empty() {
+                status!("nothing to update");
+                return Ok(false);
+            }
+            if !confirm(options.yes, options.no, true)? {
+                return Ok(false);
+            }
+            update_packages_in_transaction(
+                package_updates,
+                installed_packages,
+                prefix,
+                options,
+                cache_dir,
+                tmp_dir_prefix,
+                transaction,
+            )
+        },
+    )
+}
+
+fn get_update_plan(package_updates: &[PackageUpdate], download_only: bool) -> Vec<PlannedChange> {
+    package_updates
+        .iter()
+        .map(|pu| PlannedChange {
+            action: match download_only {
+                true => PlanAction::Download,
+                false => PlanAction::Update,
+            },
+            name: pu.name_new.clone(),
+            version: pu.version_new.clone(),
+            name_old: Some(pu.name_old.clone()),
+            version_old: Some(pu.version_old.clone()),
+            detail: None,
+        })
+        .collect()
+}
 
-    let mb = indicatif::MultiProgress::new();
+/// Downloads and applies planned updates (see `get_package_updates`) without asking.
+fn update_packages_in_transaction(
+    package_updates: Vec<PackageUpdate>,
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+    prefix: &str,
+    options: &Options,
+    cache_dir: &str,
+    tmp_dir_prefix: &str,
+    transaction: &mut Transaction,
+) -> anyhow::Result<bool> {
+    let progress_group = reporter().progress_group();
 
     let package_updates = {
         let (tx, rx) = std::sync::mpsc::channel();
         scoped_threadpool::Pool::new(MAX_CONCURRENT_DOWNLOADS).scoped(|scope| {
             for pu in &package_updates {
                 let tx = &tx;
-                let mb = &mb;
+                let progress_group = &progress_group;
                 scope.execute(move || {
                     let res = (|| {
-                        let pb = make_progress_bar(
-                            0,
-                            &pu.name_new,
-                            "{spinner} {prefix} [{wide_bar}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
-                        )?;
-                        mb.add(pb.clone());
-                        let Ok(local_fn) =
-                            download_package_if_needed(&pu.url, cache_dir, unverified_ssl, Some(&pb))
-                        else {
-                            pb.suspend(|| eprintln!("download failed"));
-                            anyhow::bail!("download failed");
+                        let progress =
+                            progress_group.progress(&pu.name_new, "", ProgressUnit::Bytes, 0);
+                        let Ok(local_fn) = download_package_if_needed(
+                            &pu.url,
+                            cache_dir,
+                            options.unverified_ssl,
+                            Some(&*progress),
+                        ) else {
+                            progress.println("download failed");
+                            anyhow::bail!(Error::Network("download failed".to_string()));
+                        };
+                        // old package is only kept for rollback if it's at hand, never downloaded
+                        let local_fn_old = match options.download_only {
+                            true => None,
+                            false => pu
+                                .url_old
+                                .as_ref()
+                                .and_then(|url_old| get_local_package_fn(url_old, cache_dir)),
                         };
                         Ok(PackageUpdate {
                             name_old: pu.name_old.clone(),
@@ -1308,11 +3756,14 @@ pub fn update_packages(
                             version_new: pu.version_new.clone(),
                             url: pu.url.clone(),
                             local_fn,
+                            repo: pu.repo.clone(),
+                            url_old: pu.url_old.clone(),
+                            local_fn_old,
                         })
                     })();
                     tx.send(res).unwrap();
                 });
-            };
+            }
         });
         drop(tx);
         let package_updates: Vec<_> = rx.iter().try_collect()?;
@@ -1320,23 +3771,33 @@ pub fn update_packages(
     }?;
 
     //mb.clear();
-    eprintln!();
+    progress_group.finish();
 
-    if download_only {
+    if options.download_only {
         return Ok(false);
     }
 
+    let mut updated = Vec::with_capacity(package_updates.len());
     for pu in package_updates {
-        let mut package_info = update_package(
+        let mut package_info = update_package_in_transaction(
             &pu.local_fn,
             &pu.name_old,
             installed_packages[&pu.name_old].clone(),
             prefix,
-            force,
+            &options.overwrite,
             tmp_dir_prefix,
+            transaction,
         )?;
 
         package_info.t = Some(UNIX_EPOCH.elapsed()?.as_secs_f64());
+        package_info.repo = pu.repo.clone();
+        let previous_old = installed_packages[&pu.name_old].previous.clone();
+        if pu.name_old != pu.name_new || pu.version_old != pu.version_new {
+            package_info.previous = Some(PreviousPackage {
+                name: pu.name_old.clone(),
+                version: pu.version_old.clone(),
+            });
+        }
         installed_packages.remove(&pu.name_old);
         installed_packages.insert(pu.name_new.clone(), package_info);
         log::info!(
@@ -1346,125 +3807,854 @@ pub fn update_packages(
             pu.name_new,
             pu.version_new
         );
-        println!(
+        status!(
             "{}-{} updated to {}-{}",
-            pu.name_old, pu.version_old, pu.name_new, pu.version_new
+            pu.name_old,
+            pu.version_old,
+            pu.name_new,
+            pu.version_new
         );
+        updated.push((pu, previous_old));
     }
 
+    // only touch the rollback files once the updates are committed
+    let cache_dir = cache_dir.to_string();
+    transaction.on_commit(move || {
+        for (pu, previous_old) in &updated {
+            keep_for_rollback(pu, previous_old.as_ref(), &cache_dir);
+        }
+    });
+
     Ok(true)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn upstall_packages(
     packages: Vec<String>,
     installed_packages: &mut HashMap<String, InstalledPackage>,
     prefix: &str,
     repos: Vec<String>,
-    force: bool,
-    offline: bool,
-    yes: bool,
-    no: bool,
-    download_only: bool,
-    unverified_ssl: bool,
+    channel: Option<Channel>,
+    options: &Options,
     cache_dir: &str,
     tmp_dir_prefix: &str,
+    history_fn: Option<&str>,
 ) -> anyhow::Result<bool> {
     let mut packages_to_install = Vec::new();
     let mut packages_to_update = Vec::new();
 
     for package in &packages {
-        let package_name = get_package_name(package);
+        let (package_name, constraint) = parse_package_spec(package)?;
+        let installed_variant = [Channel::Stable, Channel::Dev]
+            .iter()
+            .map(|x| x.package_name(&package_name))
+            .find(|x| installed_packages.contains_key(x));
         if installed_packages.contains_key(&package_name) {
             packages_to_update.push(package.clone());
+        } else if let Some(installed_name) = installed_variant
+            && constraint.is_none()
+        {
+            // installed in other channel, let the update decide which one to follow
+            packages_to_update.push(installed_name);
         } else {
             packages_to_install.push(package.clone());
         }
     }
 
-    let mut operation_occurred = false;
+    if options.adopt && !options.download_only && !packages_to_update.is_empty() {
+        anyhow::bail!(
+            "--adopt only applies to packages being installed, {} installed already",
+            packages_to_update.join(", ")
+        );
+    }
+
+    // both parts share one transaction so that failed update reverts the installs as well
+    with_transaction(
+        installed_packages,
+        tmp_dir_prefix,
+        history_fn,
+        |installed_packages, transaction| {
+            check_overwrite_globs(&options.overwrite)?;
+            let package_installs = match packages_to_install.is_empty() {
+                true => vec![],
+                false => get_package_installs(
+                    packages_to_install,
+                    installed_packages,
+                    repos.clone(),
+                    channel,
+                    options,
+                    cache_dir,
+                )?,
+            };
+            let package_updates = match packages_to_update.is_empty() {
+                true => vec![],
+                false => get_package_updates(
+                    packages_to_update,
+                    installed_packages,
+                    repos,
+                    channel,
+                    options,
+                    cache_dir,
+                )?,
+            };
+
+            // one plan and one confirmation for both parts
+            let plan =
+                get_install_plan(&package_installs, installed_packages, options.download_only)
+                    .into_iter()
+                    .chain(get_update_plan(&package_updates, options.download_only))
+                    .collect::<Vec<_>>();
+            reporter().plan(&plan);
+            if plan.is_empty() {
+                status!("nothing to update");
+                return Ok(false);
+            }
+            if !confirm(options.yes, options.no, true)? {
+                return Ok(false);
+            }
+
+            let mut operation_occurred = false;
+            if !package_installs.is_empty() {
+                let install_result = install_packages_in_transaction(
+                    package_installs,
+                    installed_packages,
+                    prefix,
+                    options,
+                    cache_dir,
+                    tmp_dir_prefix,
+                    transaction,
+                )?;
+                operation_occurred = install_result || operation_occurred;
+            }
+            if !package_updates.is_empty() {
+                let update_result = update_packages_in_transaction(
+                    package_updates,
+                    installed_packages,
+                    prefix,
+                    options,
+                    cache_dir,
+                    tmp_dir_prefix,
+                    transaction,
+                )?;
+                operation_occurred = update_result || operation_occurred;
+            }
+
+            Ok(operation_occurred)
+        },
+    )
+}
+
+/// Moves packages to given channel (`name` <-> `name.dev`) and records the channel so that
+/// further updates follow it. Held packages are refused unless `ignore_hold` is set.
+#[allow(clippy::too_many_arguments)]
+pub fn switch_channel(
+    packages: Vec<String>,
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+    channel: Channel,
+    prefix: &str,
+    repos: Vec<String>,
+    options: &Options,
+    cache_dir: &str,
+    tmp_dir_prefix: &str,
+    history_fn: Option<&str>,
+) -> anyhow::Result<bool> {
+    let mut renames = vec![];
+    for p in &packages {
+        let Some(installed_package) = installed_packages.get(p) else {
+            anyhow::bail!(Error::NotInstalled(format!("package {p} not installed")));
+        };
+        let package_name_new = channel.package_name(p);
+        if package_name_new != *p {
+            if installed_package.hold.is_some() && !options.ignore_hold {
+                anyhow::bail!("package {p} is held, use --ignore-hold to switch its channel");
+            }
+            renames.push(format!("{p}..{package_name_new}"));
+        }
+    }
+
+    let res = if renames.is_empty() {
+        false
+    } else {
+        update_packages(
+            renames,
+            installed_packages,
+            prefix,
+            repos,
+            None,
+            &Options {
+                reinstall: false,
+                download_only: false,
+                ..options.clone()
+            },
+            cache_dir,
+            tmp_dir_prefix,
+            history_fn,
+        )?
+    };
+
+    for p in &packages {
+        if let Some(installed_package) = installed_packages.get_mut(&channel.package_name(p)) {
+            installed_package.channel = Some(channel);
+        }
+    }
+
+    Ok(res)
+}
+
+/// Puts the rollback dir of cache (if any) in front of `repos`.
+fn with_rollback_repo(repos: Vec<String>, cache_dir: &str) -> Vec<String> {
+    std::iter::once(get_rollback_dir(cache_dir))
+        .filter(|x| Path::new(x).is_dir())
+        .chain(repos)
+        .collect()
+}
+
+/// Returns packages to the version they had before their last update (see `PreviousPackage`).
+#[allow(clippy::too_many_arguments)]
+pub fn rollback_packages(
+    packages: Vec<String>,
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+    prefix: &str,
+    repos: Vec<String>,
+    options: &Options,
+    cache_dir: &str,
+    tmp_dir_prefix: &str,
+    history_fn: Option<&str>,
+) -> anyhow::Result<bool> {
+    let mut rollbacks = vec![];
+    for p in &packages {
+        let Some(installed_package) = installed_packages.get(p) else {
+            anyhow::bail!(Error::NotInstalled(format!("package {p} not installed")));
+        };
+        let Some(previous) = &installed_package.previous else {
+            anyhow::bail!("no previous version of package {p} recorded");
+        };
+        rollbacks.push(format!(
+            "{p}-{}..{}-{}",
+            installed_package.version, previous.name, previous.version
+        ));
+    }
+
+    let repos = with_rollback_repo(repos, cache_dir);
+    update_packages(
+        rollbacks,
+        installed_packages,
+        prefix,
+        repos,
+        None,
+        &Options {
+            reinstall: false,
+            ignore_hold: true,
+            download_only: false,
+            ..options.clone()
+        },
+        cache_dir,
+        tmp_dir_prefix,
+        history_fn,
+    )
+}
+
+/// Manifest of the installed set (`export`) to make other machines match (`sync`).
+pub fn export_manifest(installed_packages: &HashMap<String, InstalledPackage>) -> Manifest {
+    Manifest {
+        packages: installed_packages
+            .iter()
+            .map(|(name, installed_package)| {
+                (
+                    name.clone(),
+                    ManifestPackage {
+                        version: installed_package.version.clone(),
+                        hold: installed_package.hold.clone(),
+                        channel: installed_package.channel,
+                    },
+                )
+            })
+            .collect(),
+    }
+}
+
+pub fn get_manifest(manifest_fn: &str) -> anyhow::Result<Manifest> {
+    let manifest = serde_json::from_reader(BufReader::new(File::open(manifest_fn)?))
+        .map_err(|err| anyhow::anyhow!("invalid manifest {manifest_fn}: {err}"))?;
+    Ok(manifest)
+}
+
+pub fn save_manifest(manifest: &Manifest, manifest_fn: &str) -> anyhow::Result<()> {
+    let mut f = BufWriter::new(File::create(manifest_fn)?);
+    let encoder = serde_json::ser::PrettyFormatter::with_indent(b"  ");
+    let mut ser = serde_json::Serializer::with_formatter(&mut f, encoder);
+    manifest.serialize(&mut ser)?;
+    f.write_all(b"\n")?;
+    f.flush()?;
+    Ok(())
+}
+
+/// Installs, updates and downgrades packages (and removes those not in the manifest if `remove`)
+/// so that the installed set matches `manifest`, holds and channels are taken over as well.
+#[allow(clippy::too_many_arguments)]
+pub fn sync_packages(
+    manifest: &Manifest,
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+    prefix: &str,
+    repos: Vec<String>,
+    remove: bool,
+    options: &Options,
+    cache_dir: &str,
+    tmp_dir_prefix: &str,
+    history_fn: Option<&str>,
+) -> anyhow::Result<bool> {
+    let (mut to_remove, mut to_install, mut to_update) = (vec![], vec![], vec![]);
+    let mut plan = vec![];
+    for (name, package) in &manifest.packages {
+        let version = &package.version;
+        let Some(installed_package) = installed_packages.get(name) else {
+            plan.push(PlannedChange {
+                action: PlanAction::Install,
+                name: name.clone(),
+                version: version.clone(),
+                name_old: None,
+                version_old: None,
+                detail: None,
+            });
+            to_install.push(format!("{name}-{version}"));
+            continue;
+        };
+        let version_installed = &installed_package.version;
+        if version_installed != version {
+            plan.push(PlannedChange {
+                action: get_version_change_action(version_installed, version),
+                name: name.clone(),
+                version: version.clone(),
+                name_old: Some(name.clone()),
+                version_old: Some(version_installed.clone()),
+                detail: None,
+            });
+            to_update.push(format!("{name}-{version_installed}..{name}-{version}"));
+        }
+        if installed_package.hold != package.hold {
+            plan.push(PlannedChange {
+                action: match package.hold {
+                    Some(_) => PlanAction::Hold,
+                    None => PlanAction::Unhold,
+                },
+                name: name.clone(),
+                version: version.clone(),
+                name_old: None,
+                version_old: None,
+                detail: package.hold.as_ref().and_then(|x| x.max_version.clone()),
+            });
+        }
+        if installed_package.channel != package.channel {
+            plan.push(PlannedChange {
+                action: PlanAction::SetChannel,
+                name: name.clone(),
+                version: version.clone(),
+                name_old: None,
+                version_old: None,
+                detail: package.channel.map(|x| x.to_string()),
+            });
+        }
+    }
+    for (name, installed_package) in installed_packages.iter().sorted_by_key(|x| x.0) {
+        if manifest.packages.contains_key(name) {
+            continue;
+        }
+        let version = &installed_package.version;
+        match remove {
+            true => {
+                plan.push(PlannedChange {
+                    action: PlanAction::Remove,
+                    name: name.clone(),
+                    version: version.clone(),
+                    name_old: None,
+                    version_old: None,
+                    detail: None,
+                });
+                to_remove.push(name.clone());
+            }
+            false => status!("keep {name}-{version} (not in manifest, use --remove to remove)"),
+        }
+    }
+
+    reporter().plan(&plan);
+    if plan.is_empty() {
+        status!("nothing to sync");
+        return Ok(false);
+    }
+    if !confirm(options.yes, options.no, true)? {
+        return Ok(false);
+    }
+
+    // holds and channels are part of the transaction so that they're reverted with the packages
+    with_transaction(
+        installed_packages,
+        tmp_dir_prefix,
+        history_fn,
+        |installed_packages, transaction| {
+            apply_package_changes(
+                to_remove,
+                to_install,
+                to_update,
+                installed_packages,
+                prefix,
+                repos,
+                options,
+                cache_dir,
+                tmp_dir_prefix,
+                transaction,
+            )?;
+            for (name, package) in &manifest.packages {
+                if let Some(installed_package) = installed_packages.get_mut(name) {
+                    installed_package.hold.clone_from(&package.hold);
+                    installed_package.channel = package.channel;
+                }
+            }
+            Ok(true)
+        },
+    )
+}
+
+/// Reverts transaction `id` from history provided its packages haven't been changed since and the
+/// versions to return to are still available (in repos or in the rollback dir of cache).
+#[allow(clippy::too_many_arguments)]
+pub fn undo_transaction(
+    id: u64,
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+    prefix: &str,
+    repos: Vec<String>,
+    options: &Options,
+    cache_dir: &str,
+    tmp_dir_prefix: &str,
+    history_fn: &str,
+) -> anyhow::Result<bool> {
+    let Some(entry) = get_history(history_fn)?.into_iter().find(|x| x.id == id) else {
+        anyhow::bail!("transaction {id} not found in history");
+    };
+    if let Some(err) = &entry.error {
+        anyhow::bail!("transaction {id} failed ({err}), nothing to undo");
+    }
+    if entry.changes.is_empty() {
+        anyhow::bail!("transaction {id} changed no packages, nothing to undo");
+    }
+
+    let (mut to_remove, mut to_install, mut to_update) = (vec![], vec![], vec![]);
+    let mut plan = vec![];
+    for change in &entry.changes {
+        let name = &change.name;
+        let version_current = installed_packages.get(name).map(|x| x.version.clone());
+        if version_current != change.version_new {
+            anyhow::bail!(Error::Conflict(format!(
+                "package {name} changed since transaction {id}, can't undo"
+            )));
+        }
+        let (action, version, version_old) = match (&change.version_old, &change.version_new) {
+            (None, Some(new)) => {
+                to_remove.push(name.clone());
+                (PlanAction::Remove, new, None)
+            }
+            (Some(old), None) => {
+                to_install.push(format!("{name}-{old}"));
+                (PlanAction::Install, old, None)
+            }
+            (Some(old), Some(new)) => {
+                to_update.push(format!("{name}-{new}..{name}-{old}"));
+                (get_version_change_action(new, old), old, Some(new.clone()))
+            }
+            (None, None) => continue,
+        };
+        plan.push(PlannedChange {
+            action,
+            name: name.clone(),
+            version: version.clone(),
+            name_old: version_old.as_ref().map(|_| name.clone()),
+            version_old,
+            detail: None,
+        });
+    }
+    reporter().plan(&plan);
+    if !confirm(options.yes, options.no, false)? {
+        return Ok(false);
+    }
+
+    with_transaction(
+        installed_packages,
+        tmp_dir_prefix,
+        Some(history_fn),
+        |installed_packages, transaction| {
+            apply_package_changes(
+                to_remove,
+                to_install,
+                to_update,
+                installed_packages,
+                prefix,
+                repos,
+                options,
+                cache_dir,
+                tmp_dir_prefix,
+                transaction,
+            )?;
+            Ok(true)
+        },
+    )
+}
 
-    if !packages_to_install.is_empty() {
-        let install_result = install_packages(
-            packages_to_install,
+/// Removes, installs and updates (`from..to` specs) packages in `transaction` without asking (the
+/// caller has presented the changes already). The rollback dir of cache is used as a repo too.
+#[allow(clippy::too_many_arguments)]
+fn apply_package_changes(
+    to_remove: Vec<String>,
+    to_install: Vec<String>,
+    to_update: Vec<String>,
+    installed_packages: &mut HashMap<String, InstalledPackage>,
+    prefix: &str,
+    repos: Vec<String>,
+    options: &Options,
+    cache_dir: &str,
+    tmp_dir_prefix: &str,
+    transaction: &mut Transaction,
+) -> anyhow::Result<()> {
+    let repos = with_rollback_repo(repos, cache_dir);
+    let options = &Options {
+        reinstall: false,
+        adopt: false,
+        ignore_hold: true,
+        download_only: false,
+        ..options.clone()
+    };
+    check_overwrite_globs(&options.overwrite)?;
+    for name in &to_remove {
+        remove_package_in_transaction(name, installed_packages[name].clone(), prefix, transaction)?;
+        installed_packages.remove(name);
+    }
+    if !to_install.is_empty() {
+        let package_installs = get_package_installs(
+            to_install,
             installed_packages,
-            prefix,
             repos.clone(),
-            force,
-            offline,
-            yes,
-            no,
-            download_only,
-            unverified_ssl,
+            None,
+            options,
+            cache_dir,
+        )?;
+        install_packages_in_transaction(
+            package_installs,
+            installed_packages,
+            prefix,
+            options,
             cache_dir,
             tmp_dir_prefix,
+            transaction,
         )?;
-        operation_occurred = install_result || operation_occurred;
     }
-
-    if !packages_to_update.is_empty() {
-        let update_result = update_packages(
-            packages_to_update,
+    if !to_update.is_empty() {
+        let package_updates = get_package_updates(
+            to_update,
             installed_packages,
-            prefix,
             repos,
-            force,
-            offline,
-            yes,
-            no,
-            download_only,
-            unverified_ssl,
+            None,
+            options,
+            cache_dir,
+        )?;
+        update_packages_in_transaction(
+            package_updates,
+            installed_packages,
+            prefix,
+            options,
             cache_dir,
             tmp_dir_prefix,
+            transaction,
         )?;
-        operation_occurred = update_result || operation_occurred;
     }
+    Ok(())
+}
+
+/// Finding of `check_packages`, `modified_config` (changed backup file) is informational only.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CheckIssue {
+    pub package: String,
+    pub path: String,
+    pub kind: CheckIssueKind,
+    /// Permissions for `permission_difference`, error for `repair_failed`.
+    pub detail: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckIssueKind {
+    Missing,
+    NotAFile,
+    NotADirectory,
+    ChecksumDifference,
+    PermissionDifference,
+    ModifiedConfig,
+    Restored,
+    RepairFailed,
+}
+
+impl CheckIssue {
+    fn new(package: &str, path: &str, kind: CheckIssueKind, detail: Option<String>) -> Self {
+        CheckIssue {
+            package: package.to_string(),
+            path: path.to_string(),
+            kind,
+            detail,
+        }
+    }
+}
+
+impl std::fmt::Display for CheckIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            CheckIssueKind::Missing => "does not exist",
+            CheckIssueKind::NotAFile => "not a file",
+            CheckIssueKind::NotADirectory => "not a directory",
+            CheckIssueKind::ChecksumDifference => "checksum difference",
+            CheckIssueKind::PermissionDifference => "permission difference",
+            CheckIssueKind::ModifiedConfig => "modified config",
+            CheckIssueKind::Restored => "restored",
+            CheckIssueKind::RepairFailed => "repair failed",
+        };
+        write!(f, "{}: {kind}: {}", self.package, self.path)?;
+        if let Some(detail) = &self.detail {
+            write!(f, " ({detail})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Issue counts by category, `errors` excludes modified config files and repaired ones.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CheckSummary {
+    pub missing: u32,
+    pub wrong_type: u32,
+    pub checksum_difference: u32,
+    pub permission_difference: u32,
+    pub modified_config: u32,
+    pub restored: u32,
+    pub errors: u32,
+}
+
+impl CheckSummary {
+    fn new(issues: &[CheckIssue]) -> Self {
+        let mut summary = CheckSummary::default();
+        for issue in issues {
+            match issue.kind {
+                CheckIssueKind::Missing => summary.missing += 1,
+                CheckIssueKind::NotAFile | CheckIssueKind::NotADirectory => summary.wrong_type += 1,
+                CheckIssueKind::ChecksumDifference => summary.checksum_difference += 1,
+                CheckIssueKind::PermissionDifference => summary.permission_difference += 1,
+                CheckIssueKind::ModifiedConfig => summary.modified_config += 1,
+                CheckIssueKind::Restored => summary.restored += 1,
+                CheckIssueKind::RepairFailed => {}
+            }
+        }
+        summary.errors = summary.missing
+            + summary.wrong_type
+            + summary.checksum_difference
+            + summary.permission_difference
+            - summary.restored;
+        summary
+    }
+}
+
+impl std::fmt::Display for CheckSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "missing: {}, wrong type: {}, checksum difference: {}, permission difference: {}, modified config: {}, restored: {}",
+            self.missing,
+            self.wrong_type,
+            self.checksum_difference,
+            self.permission_difference,
+            self.modified_config,
+            self.restored
+        )
+    }
+}
 
-    Ok(operation_occurred)
+/// Result of `check_packages`, printed as json with json output.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CheckReport {
+    pub issues: Vec<CheckIssue>,
+    pub summary: CheckSummary,
 }
 
-fn check_package(package_name: &str, pkg: &InstalledPackage, prefix: &str) -> anyhow::Result<u32> {
+/// Returns the issues and the files (and dirs) that can be repaired. Unless
+/// `full` is set, files whose size and mtime match the recorded ones are not hashed.
+fn check_package(
+    package_name: &str,
+    pkg: &InstalledPackage,
+    prefix: &str,
+    full: bool,
+    progress_group: &dyn ProgressGroup,
+) -> anyhow::Result<(Vec<CheckIssue>, Vec<String>)> {
     let mut res = vec![];
+    let mut repairable = vec![];
 
-    let progress_bar = make_progress_bar(
-        pkg.md5sums.len().try_into()?,
+    let progress = progress_group.progress(
         package_name,
-        "{spinner} {prefix} [{wide_bar}] {pos}/{len}",
-    )?;
+        "",
+        ProgressUnit::Items,
+        pkg.md5sums.len().try_into()?,
+    );
 
-    let mut err_count = 0;
-    for (fn_name, md5sum) in progress_bar.wrap_iter(pkg.md5sums.iter()) {
+    let backup = pkg.backup.clone().unwrap_or_default();
+    for (fn_name, md5sum) in pkg.md5sums.iter().sorted().inspect(|_| progress.inc(1)) {
         let file_path = format!("{prefix}/{fn_name}");
-        if !Path::new(&file_path).exists() {
-            res.push(format!("{package_name}: does not exist: {file_path}"));
-            err_count += 1;
+        let is_backup = backup.contains(fn_name);
+        let Ok(metadata) = std::fs::metadata(&file_path) else {
+            res.push(CheckIssue::new(
+                package_name,
+                &file_path,
+                CheckIssueKind::Missing,
+                None,
+            ));
+            if !is_backup {
+                repairable.push(fn_name.clone());
+            }
+            continue;
+        };
+        if metadata.is_dir() != md5sum.is_none() {
+            let kind = match md5sum {
+                Some(_) => CheckIssueKind::NotAFile,
+                None => CheckIssueKind::NotADirectory,
+            };
+            res.push(CheckIssue::new(package_name, &file_path, kind, None));
+            continue;
         }
-        if let Some(md5sum) = md5sum {
-            if pkg.backup.clone().unwrap_or_default().contains(fn_name) {
-                continue;
+        let file_stat = pkg.file_stats.as_ref().and_then(|x| x.get(fn_name));
+        let current_stat = FileStat::from_metadata(&metadata)?;
+        let mode_changed =
+            file_stat.is_some_and(|x| x.mode.is_some() && x.mode != current_stat.mode);
+        let content_changed = match md5sum {
+            Some(_)
+                if !full
+                    && file_stat.is_some_and(|x| {
+                        x.size == current_stat.size && x.mtime == current_stat.mtime
+                    }) =>
+            {
+                log::trace!("stat unchanged, skipping {file_path}");
+                false
             }
-            if let Ok(current_md5sum) = get_md5_sum(&file_path) {
-                if current_md5sum != *md5sum {
-                    res.push(format!("{package_name}: checksum difference: {file_path}"));
-                    err_count += 1;
-                }
+            Some(md5sum) => get_md5_sum(&file_path).is_ok_and(|x| x != *md5sum),
+            None => false,
+        };
+        if is_backup {
+            if mode_changed || content_changed {
+                res.push(CheckIssue::new(
+                    package_name,
+                    &file_path,
+                    CheckIssueKind::ModifiedConfig,
+                    None,
+                ));
             }
+            continue;
+        }
+        if content_changed {
+            res.push(CheckIssue::new(
+                package_name,
+                &file_path,
+                CheckIssueKind::ChecksumDifference,
+                None,
+            ));
+        } else if mode_changed {
+            let detail = format!(
+                "{:o}, expected {:o}",
+                current_stat.mode.unwrap_or_default(),
+                file_stat.and_then(|x| x.mode).unwrap_or_default(),
+            );
+            res.push(CheckIssue::new(
+                package_name,
+                &file_path,
+                CheckIssueKind::PermissionDifference,
+                Some(detail),
+            ));
+        }
+        if content_changed || mode_changed {
+            repairable.push(fn_name.clone());
         }
     }
 
-    progress_bar.finish();
-    eprintln!();
+    progress.finish();
 
-    for r in res {
-        println!("{r}");
-    }
+    Ok((res, repairable))
+}
+
+/// Restores `files` of installed package from the package file of the installed version, files
+/// are verified against the recorded checksums. Returns the restored paths.
+#[allow(clippy::too_many_arguments)]
+fn repair_package(
+    package_name: &str,
+    installed_package: &InstalledPackage,
+    files: &[String],
+    prefix: &str,
+    available_packages: &HashMap<String, Vec<String>>,
+    unverified_ssl: bool,
+    cache_dir: &str,
+    tmp_dir_prefix: &str,
+) -> anyhow::Result<Vec<String>> {
+    let (_, constraint) =
+        parse_package_spec(&format!("{package_name}-{}", installed_package.version))?;
+    let url = get_package_url(package_name, constraint.as_slice(), available_packages)?;
+    let local_fn = download_package_if_needed(&url, cache_dir, unverified_ssl, None)?;
 
-    Ok(err_count)
+    let tmp_dir = tempfile::Builder::new().tempdir_in(tmp_dir_prefix)?;
+    let tmp_dir_path = as_unix_path(tmp_dir.path());
+    unzip_to(&local_fn, &tmp_dir_path, package_name)?;
+
+    let mut transaction = Transaction::new(tmp_dir_prefix, None)?;
+    let res = (|| {
+        let mut restored = vec![];
+        // parent dirs first
+        for f in files.iter().sorted_by_key(|x| x.len()) {
+            let src_fn = format!("{tmp_dir_path}/{f}");
+            let target_fn = format!("{prefix}/{f}");
+            match installed_package.md5sums.get(f) {
+                Some(None) => {
+                    transaction.create_dir(&target_fn)?;
+                    let src_info = std::fs::metadata(&src_fn)?;
+                    let mod_time = src_info.modified().unwrap_or(std::time::SystemTime::now());
+                    transaction.set_dir_attrs(&target_fn, src_info.permissions(), mod_time)?;
+                }
+                Some(Some(md5sum)) => {
+                    if get_md5_sum(&src_fn)? != *md5sum {
+                        anyhow::bail!(Error::Integrity(format!(
+                            "{f} in {local_fn} doesn't match the installed one"
+                        )));
+                    }
+                    transaction.put_file(&src_fn, &target_fn)?;
+                }
+                None => continue,
+            }
+            restored.push(target_fn);
+        }
+        Ok(restored)
+    })();
+    match res {
+        Ok(restored) => {
+            transaction.commit(None)?;
+            Ok(restored)
+        }
+        Err(err) => {
+            transaction.rollback()?;
+            Err(err)
+        }
+    }
 }
 
+/// Checks files of installed packages (existence, type, checksum and permissions), changes of
+/// backup files are only reported as modified config. With `repair` missing and corrupted files
+/// (except for backup ones) are restored from the package file of the installed version and their
+/// stats are updated in `installed_packages`. With
+/// `full` all files are hashed, otherwise only those whose size or mtime changed since install.
+/// Packages are checked in parallel, the report goes to `Reporter::check_report`.
 pub fn check_packages(
     packages: Vec<String>,
-    installed_packages: &HashMap<String, InstalledPackage>,
+    installed_packages: &mut HashMap<String, InstalledPackage>,
     prefix: &str,
+    repos: Vec<String>,
+    options: &Options,
+    cache_dir: &str,
+    tmp_dir_prefix: &str,
 ) -> anyhow::Result<()> {
     for package in &packages {
         let (package_name, package_version) = split_package_name_version(package);
@@ -1472,20 +4662,98 @@ pub fn check_packages(
             || (!package_version.is_empty()
                 && package_version != installed_packages[&package_name].version)
         {
-            anyhow::bail!("{package_name} not installed");
+            anyhow::bail!(Error::NotInstalled(format!("{package_name} not installed")));
         }
     }
 
-    let mut err_count = 0;
-    for package in &packages {
-        let package_name = split_package_name_version(package).0;
-        if let Some(installed_package) = installed_packages.get(&package_name) {
-            err_count += check_package(package, installed_package, prefix)?;
+    let progress_group = reporter().progress_group();
+
+    let results = {
+        let (tx, rx) = std::sync::mpsc::channel();
+        scoped_threadpool::Pool::new(MAX_CONCURRENT_CHECKS).scoped(|scope| {
+            for (i, package) in packages.iter().enumerate() {
+                let package_name = split_package_name_version(package).0;
+                let Some(installed_package) = installed_packages.get(&package_name) else {
+                    continue;
+                };
+                let tx = &tx;
+                let progress_group = &*progress_group;
+                scope.execute(move || {
+                    let res = check_package(
+                        package,
+                        installed_package,
+                        prefix,
+                        options.full,
+                        progress_group,
+                    );
+                    tx.send((i, package_name, res)).unwrap();
+                });
+            }
+        });
+        drop(tx);
+        // report in the order the packages were given
+        rx.iter().sorted_by_key(|x| x.0).collect::<Vec<_>>()
+    };
+
+    progress_group.finish();
+
+    let mut issues = vec![];
+    let mut to_repair = vec![];
+    for (_, package_name, res) in results {
+        let (package_issues, repairable) = res?;
+        issues.extend(package_issues);
+        if !repairable.is_empty() {
+            to_repair.push((package_name, repairable));
+        }
+    }
+
+    if options.repair && !to_repair.is_empty() {
+        let available_packages = get_available_packages(
+            with_rollback_repo(repos, cache_dir),
+            options.offline,
+            options.unverified_ssl,
+        )?;
+        for (package_name, files) in to_repair {
+            let installed_package = installed_packages.get_mut(&package_name).unwrap();
+            match repair_package(
+                &package_name,
+                installed_package,
+                &files,
+                prefix,
+                &available_packages,
+                options.unverified_ssl,
+                cache_dir,
+                tmp_dir_prefix,
+            ) {
+                Ok(restored) => {
+                    // restored files have new stats, the quick check would see them as changed
+                    if let Some(file_stats) = &mut installed_package.file_stats {
+                        for f in &files {
+                            file_stats.insert(f.clone(), FileStat::new(&format!("{prefix}/{f}"))?);
+                        }
+                    }
+                    issues.extend(restored.iter().map(|path| {
+                        CheckIssue::new(&package_name, path, CheckIssueKind::Restored, None)
+                    }));
+                }
+                Err(err) => issues.extend(files.iter().map(|f| {
+                    CheckIssue::new(
+                        &package_name,
+                        &format!("{prefix}/{f}"),
+                        CheckIssueKind::RepairFailed,
+                        Some(err.to_string()),
+                    )
+                })),
+            }
         }
     }
 
+    let summary = CheckSummary::new(&issues);
+    let err_count = summary.errors;
+    reporter().check_report(&CheckReport { issues, summary });
+
     if err_count > 0 {
-        anyhow::bail!("error count: {err_count}");
+        anyhow::bail!(Error::Integrity(format!("error count: {err_count}")));
     }
 
     Ok(())
@@ -1503,6 +4771,55 @@ fn gen_fn_to_package_name_mapping(
     fn_to_package_name
 }
 
+fn get_prefix_relative_path(path: &str, prefix: &str) -> String {
+    let path = Path::new(path);
+    let path = path.strip_prefix(prefix).unwrap_or(path);
+    as_unix_path(path)
+}
+
+pub fn get_owners(
+    paths: Vec<String>,
+    installed_packages: &HashMap<String, InstalledPackage>,
+    prefix: &str,
+) -> anyhow::Result<Vec<OwnedPath>> {
+    let fn_to_package_name = gen_fn_to_package_name_mapping(installed_packages);
+
+    let mut ret = Vec::with_capacity(paths.len());
+    for path in paths {
+        let path = get_prefix_relative_path(&path, prefix);
+        let Some(package_name) = fn_to_package_name.get(&path) else {
+            ret.push(OwnedPath { path, owner: None });
+            continue;
+        };
+        let pkg = &installed_packages[package_name];
+        let target_fn = format!("{prefix}/{path}");
+        let is_missing = !Path::new(&target_fn).exists();
+        let md5sum = pkg.md5sums[&path].as_ref();
+        // unreadable path or one of other type counts as modified instead of failing the query
+        let is_modified = match md5sum {
+            _ if is_missing => false,
+            Some(md5sum) => !get_md5_sum(&target_fn)
+                .inspect_err(|err| log::warn!("failed to get md5 sum of {target_fn}: {err}"))
+                .is_ok_and(|x| x == *md5sum),
+            None => !Path::new(&target_fn).is_dir(),
+        };
+        let owner = PathOwner {
+            package_name: package_name.clone(),
+            version: pkg.version.clone(),
+            is_dir: md5sum.is_none(),
+            is_backup: pkg.backup.clone().unwrap_or_default().contains(&path),
+            is_modified,
+            is_missing,
+        };
+        ret.push(OwnedPath {
+            path,
+            owner: Some(owner),
+        });
+    }
+
+    Ok(ret)
+}
+
 pub fn get_untracked(
     paths: Vec<String>,
     installed_packages: &HashMap<String, InstalledPackage>,
@@ -1526,41 +4843,630 @@ pub fn get_untracked(
         paths
     };
 
-    let mut ret = Vec::new();
+    let mut ret = Vec::new();
+
+    for path in paths.into_iter() {
+        let full_path = format!("{prefix}/{path}");
+        let (dirs, files) = get_recursive_listing(&full_path)?;
+
+        let progress = reporter().progress(
+            &path,
+            "",
+            ProgressUnit::Items,
+            (dirs.len() + files.len()).try_into()?,
+        );
+
+        for dir_name in dirs.into_iter().inspect(|_| progress.inc(1)) {
+            let full_dir_name = format!("{path}/{dir_name}");
+            if !fn_to_package_name.contains_key(&full_dir_name) {
+                ret.push(full_dir_name);
+            }
+        }
+        for fn_name in files.into_iter().inspect(|_| progress.inc(1)) {
+            let full_fn_name = format!("{path}/{fn_name}");
+            if !fn_to_package_name.contains_key(&full_fn_name) {
+                ret.push(full_fn_name);
+            }
+        }
+
+        progress.finish();
+    }
+
+    Ok(ret)
+}
+
+/// Settings of package operations, can be changed between operations with `Atxpkg::options_mut`.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Globs of untracked files allowed to be overwritten.
+    pub overwrite: Vec<String>,
+    pub reinstall: bool,
+    pub adopt: bool,
+    pub ignore_hold: bool,
+    pub download_only: bool,
+    pub yes: bool,
+    pub no: bool,
+    pub offline: bool,
+    pub unverified_ssl: bool,
+    /// Hash all files in `check`.
+    pub full: bool,
+    /// Restore missing and corrupted files in `check`.
+    pub repair: bool,
+}
+
+/// Builder of `Atxpkg`, see `Atxpkg::builder`.
+#[derive(Clone)]
+pub struct AtxpkgBuilder {
+    root_dir: String,
+    prefix: String,
+    repos: Option<Vec<String>>,
+    channel: Option<Channel>,
+    read_only: bool,
+    wait: bool,
+    command: String,
+    options: Options,
+    reporter: Arc<dyn Reporter>,
+}
+
+impl AtxpkgBuilder {
+    /// Installation prefix, `/` (`c:/` on windows) by default.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Repositories to use instead of the ones listed in `repos.txt` of the root dir.
+    pub fn repos(mut self, repos: Vec<String>) -> Self {
+        self.repos = Some(repos);
+        self
+    }
+
+    /// Channel to use instead of the one stored in `channel.txt` of the root dir.
+    pub fn channel(mut self, channel: Channel) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    /// Lock the root dir shared, operations changing packages then fail.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Wait for the lock held by another atxpkg instead of failing.
+    pub fn wait(mut self, wait: bool) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    /// Command shown to other atxpkg instances waiting for the lock and recorded in history.
+    pub fn command(mut self, command: &str) -> Self {
+        self.command = command.to_string();
+        self
+    }
+
+    pub fn options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Reporter of the session's operations, `SilentReporter` by default.
+    pub fn reporter(mut self, reporter: Arc<dyn Reporter>) -> Self {
+        self.reporter = reporter;
+        self
+    }
+
+    pub fn overwrite(mut self, overwrite: &[String]) -> Self {
+        self.options.overwrite = overwrite.to_vec();
+        self
+    }
+
+    pub fn reinstall(mut self, reinstall: bool) -> Self {
+        self.options.reinstall = reinstall;
+        self
+    }
+
+    pub fn adopt(mut self, adopt: bool) -> Self {
+        self.options.adopt = adopt;
+        self
+    }
+
+    pub fn ignore_hold(mut self, ignore_hold: bool) -> Self {
+        self.options.ignore_hold = ignore_hold;
+        self
+    }
+
+    pub fn download_only(mut self, download_only: bool) -> Self {
+        self.options.download_only = download_only;
+        self
+    }
+
+    pub fn yes(mut self, yes: bool) -> Self {
+        self.options.yes = yes;
+        self
+    }
+
+    pub fn no(mut self, no: bool) -> Self {
+        self.options.no = no;
+        self
+    }
+
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.options.offline = offline;
+        self
+    }
+
+    pub fn unverified_ssl(mut self, unverified_ssl: bool) -> Self {
+        self.options.unverified_ssl = unverified_ssl;
+        self
+    }
+
+    /// Locks the root dir, creates its cache and tmp subdirs and finishes an interrupted operation.
+    pub fn open(self) -> anyhow::Result<Atxpkg> {
+        let session = SessionScope {
+            reporter: self.reporter.clone(),
+            command: self.command.clone(),
+        };
+        in_session(&session, || self.open_inner())
+    }
+
+    fn open_inner(self) -> anyhow::Result<Atxpkg> {
+        let root_dir = self.root_dir;
+        if !Path::new(&root_dir).exists() {
+            anyhow::bail!("root dir {root_dir} does not exist");
+        }
+        if !Path::new(&self.prefix).exists() {
+            anyhow::bail!("prefix dir {} does not exist", self.prefix);
+        }
+
+        let cache_dir = format!("{root_dir}/cache");
+        let tmp_dir_prefix = format!("{root_dir}/tmp");
+
+        // unfinished operation is recovered below so even read-only sessions have to lock
+        // exclusively, checked again once locked as another instance might have crashed meanwhile
+        let db_fn = format!("{root_dir}/installed.json");
+        let needs_recover = || -> anyhow::Result<bool> {
+            Ok(Path::new(&tmp_dir_prefix).exists()
+                && needs_recover(&tmp_dir_prefix, &get_installed_packages(&db_fn)?)?)
+        };
+        let exclusive = !self.read_only || needs_recover()?;
+        let mut lock = lock_root_dir(&root_dir, exclusive, self.wait, &self.command)?;
+        if !exclusive && needs_recover()? {
+            drop(lock);
+            lock = lock_root_dir(&root_dir, true, self.wait, &self.command)?;
+        }
+
+        if !Path::new(&cache_dir).exists() {
+            log::info!("creating cache dir {cache_dir}");
+            std::fs::create_dir(&cache_dir)?;
+        }
+        if !Path::new(&tmp_dir_prefix).exists() {
+            log::info!("creating tmp dir {tmp_dir_prefix}");
+            std::fs::create_dir(&tmp_dir_prefix)?;
+        }
+
+        let repos_fn = format!("{root_dir}/repos.txt");
+        let mut repos = vec![cache_dir.clone()];
+        match self.repos {
+            Some(x) => repos.extend(x),
+            None if Path::new(&repos_fn).exists() => repos.extend(read_lines(&repos_fn)?),
+            None => {}
+        }
+        log::debug!("repos: {repos:?}");
+
+        let channel_fn = format!("{root_dir}/channel.txt");
+        let channel = match self.channel {
+            Some(x) => Some(x),
+            None => get_global_channel(&channel_fn)?,
+        };
+        log::debug!("channel: {channel:?}");
+
+        // operation interrupted by a crash has to be finished before doing anything else
+        let mut installed_packages = get_installed_packages(&db_fn)?;
+        let recovered = recover(&mut installed_packages, &tmp_dir_prefix)?;
+        if recovered {
+            save_installed_packages(&installed_packages, &db_fn)?;
+            log::info!("recover completed");
+            status!("recover completed");
+        }
+
+        Ok(Atxpkg {
+            history_fn: format!("{root_dir}/history.jsonl"),
+            root_dir,
+            prefix: self.prefix,
+            repos,
+            channel,
+            channel_fn,
+            cache_dir,
+            tmp_dir_prefix,
+            db_fn,
+            read_only: self.read_only,
+            recovered,
+            options: self.options,
+            session: SessionScope {
+                reporter: self.reporter,
+                command: self.command,
+            },
+            _lock: lock,
+        })
+    }
+}
+
+/// Session of atxpkg over a root dir (installed database, cache, history...), holds the root dir
+/// lock until dropped. The free functions are the building blocks it's made of.
+///
+/// Operations that change packages save the installed database even when they fail, changes of a
+/// failed operation have been rolled back by then.
+pub struct Atxpkg {
+    root_dir: String,
+    prefix: String,
+    repos: Vec<String>,
+    channel: Option<Channel>,
+    channel_fn: String,
+    cache_dir: String,
+    tmp_dir_prefix: String,
+    db_fn: String,
+    history_fn: String,
+    read_only: bool,
+    recovered: bool,
+    options: Options,
+    session: SessionScope,
+    _lock: RootDirLock,
+}
+
+impl Atxpkg {
+    pub fn builder(root_dir: &str) -> AtxpkgBuilder {
+        AtxpkgBuilder {
+            root_dir: root_dir.to_string(),
+            prefix: if cfg!(windows) { "c:/" } else { "/" }.to_string(),
+            repos: None,
+            channel: None,
+            read_only: false,
+            wait: false,
+            command: std::env::args().join(" "),
+            options: Options::default(),
+            reporter: Arc::new(SilentReporter),
+        }
+    }
+
+    pub fn root_dir(&self) -> &str {
+        &self.root_dir
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Repositories including the cache dir.
+    pub fn repos(&self) -> &[String] {
+        &self.repos
+    }
+
+    pub fn channel(&self) -> Option<Channel> {
+        self.channel
+    }
+
+    pub fn channel_fn(&self) -> &str {
+        &self.channel_fn
+    }
+
+    pub fn cache_dir(&self) -> &str {
+        &self.cache_dir
+    }
+
+    pub fn tmp_dir_prefix(&self) -> &str {
+        &self.tmp_dir_prefix
+    }
+
+    pub fn db_fn(&self) -> &str {
+        &self.db_fn
+    }
+
+    pub fn history_fn(&self) -> &str {
+        &self.history_fn
+    }
+
+    /// Whether an interrupted operation has been finished when opening.
+    pub fn recovered(&self) -> bool {
+        self.recovered
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn options_mut(&mut self) -> &mut Options {
+        &mut self.options
+    }
+
+    pub fn reporter(&self) -> &Arc<dyn Reporter> {
+        &self.session.reporter
+    }
+
+    /// Runs `f` with the session's reporter and command.
+    fn scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        in_session(&self.session, f)
+    }
+
+    pub fn installed_packages(&self) -> anyhow::Result<HashMap<String, InstalledPackage>> {
+        get_installed_packages(&self.db_fn)
+    }
+
+    /// Runs `f` on the installed database and saves it afterwards (even on error).
+    pub fn modify<T>(
+        &self,
+        f: impl FnOnce(&mut HashMap<String, InstalledPackage>) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        if self.read_only {
+            anyhow::bail!("{} opened read-only", self.root_dir);
+        }
+        let mut installed_packages = self.installed_packages()?;
+        let res = self.scope(|| f(&mut installed_packages));
+        save_installed_packages(&installed_packages, &self.db_fn)?;
+        if let Err(err) = remove_finished_journals(&self.tmp_dir_prefix, &installed_packages) {
+            log::warn!("failed to remove journals: {err}");
+        }
+        res
+    }
+
+    pub fn install(&self, packages: Vec<String>) -> anyhow::Result<bool> {
+        self.modify(|installed_packages| {
+            install_packages(
+                packages,
+                installed_packages,
+                &self.prefix,
+                self.repos.clone(),
+                self.channel,
+                &self.options,
+                &self.cache_dir,
+                &self.tmp_dir_prefix,
+                Some(&self.history_fn),
+            )
+        })
+    }
+
+    /// Updates `packages`, all installed ones if empty.
+    pub fn update(&self, packages: Vec<String>) -> anyhow::Result<bool> {
+        self.modify(|installed_packages| {
+            update_packages(
+                packages,
+                installed_packages,
+                &self.prefix,
+                self.repos.clone(),
+                self.channel,
+                &self.options,
+                &self.cache_dir,
+                &self.tmp_dir_prefix,
+                Some(&self.history_fn),
+            )
+        })
+    }
+
+    pub fn upstall(&self, packages: Vec<String>) -> anyhow::Result<bool> {
+        self.modify(|installed_packages| {
+            upstall_packages(
+                packages,
+                installed_packages,
+                &self.prefix,
+                self.repos.clone(),
+                self.channel,
+                &self.options,
+                &self.cache_dir,
+                &self.tmp_dir_prefix,
+                Some(&self.history_fn),
+            )
+        })
+    }
 
-    for path in paths.into_iter() {
-        let full_path = format!("{prefix}/{path}");
-        let (dirs, files) = get_recursive_listing(&full_path)?;
+    pub fn remove(&self, packages: Vec<String>) -> anyhow::Result<bool> {
+        self.modify(|installed_packages| {
+            remove_packages(
+                packages,
+                installed_packages,
+                &self.prefix,
+                &self.options,
+                &self.tmp_dir_prefix,
+                Some(&self.history_fn),
+            )
+        })
+    }
 
-        let progress_bar = make_progress_bar(
-            (dirs.len() + files.len()).try_into()?,
-            &path,
-            "{spinner} {prefix} [{wide_bar}] {pos}/{len}",
-        )?;
+    /// Returns `packages` to the version they had before their last update.
+    pub fn rollback(&self, packages: Vec<String>) -> anyhow::Result<bool> {
+        self.modify(|installed_packages| {
+            rollback_packages(
+                packages,
+                installed_packages,
+                &self.prefix,
+                self.repos.clone(),
+                &self.options,
+                &self.cache_dir,
+                &self.tmp_dir_prefix,
+                Some(&self.history_fn),
+            )
+        })
+    }
 
-        for dir_name in progress_bar.wrap_iter(dirs.into_iter()) {
-            let full_dir_name = format!("{path}/{dir_name}");
-            if !fn_to_package_name.contains_key(&full_dir_name) {
-                ret.push(full_dir_name);
+    /// Writes installed packages to `manifest_fn`, see `export_manifest`.
+    pub fn export(&self, manifest_fn: &str) -> anyhow::Result<Manifest> {
+        let manifest = export_manifest(&self.installed_packages()?);
+        save_manifest(&manifest, manifest_fn)?;
+        Ok(manifest)
+    }
+
+    /// Makes installed packages match `manifest`, packages not in it are removed with `remove`.
+    pub fn sync(&self, manifest: &Manifest, remove: bool) -> anyhow::Result<bool> {
+        self.modify(|installed_packages| {
+            sync_packages(
+                manifest,
+                installed_packages,
+                &self.prefix,
+                self.repos.clone(),
+                remove,
+                &self.options,
+                &self.cache_dir,
+                &self.tmp_dir_prefix,
+                Some(&self.history_fn),
+            )
+        })
+    }
+
+    /// Reverts transaction `id` from history.
+    pub fn undo(&self, id: u64) -> anyhow::Result<bool> {
+        self.modify(|installed_packages| {
+            undo_transaction(
+                id,
+                installed_packages,
+                &self.prefix,
+                self.repos.clone(),
+                &self.options,
+                &self.cache_dir,
+                &self.tmp_dir_prefix,
+                &self.history_fn,
+            )
+        })
+    }
+
+    /// Moves `packages` to `channel`, with no packages the global channel is set instead (packages
+    /// follow it on update).
+    pub fn switch_channel(&self, channel: Channel, packages: Vec<String>) -> anyhow::Result<bool> {
+        if packages.is_empty() {
+            if self.read_only {
+                anyhow::bail!("{} opened read-only", self.root_dir);
             }
+            save_global_channel(channel, &self.channel_fn)?;
+            return Ok(false);
         }
-        for fn_name in progress_bar.wrap_iter(files.into_iter()) {
-            let full_fn_name = format!("{path}/{fn_name}");
-            if !fn_to_package_name.contains_key(&full_fn_name) {
-                ret.push(full_fn_name);
-            }
+        self.modify(|installed_packages| {
+            switch_channel(
+                packages,
+                installed_packages,
+                channel,
+                &self.prefix,
+                self.repos.clone(),
+                &self.options,
+                &self.cache_dir,
+                &self.tmp_dir_prefix,
+                Some(&self.history_fn),
+            )
+        })
+    }
+
+    /// Holds `packages` (allowing updates up to `max_version` if given).
+    pub fn hold(&self, packages: Vec<String>, max_version: Option<String>) -> anyhow::Result<()> {
+        self.modify(|installed_packages| hold_packages(packages, installed_packages, max_version))
+    }
+
+    pub fn unhold(&self, packages: Vec<String>) -> anyhow::Result<()> {
+        self.modify(|installed_packages| unhold_packages(packages, installed_packages))
+    }
+
+    /// Checks `packages`, all installed ones if empty.
+    pub fn check(&self, packages: Vec<String>) -> anyhow::Result<()> {
+        let check = |installed_packages: &mut HashMap<String, InstalledPackage>| {
+            let packages = match packages.is_empty() {
+                true => installed_packages.keys().cloned().sorted().collect(),
+                false => packages,
+            };
+            check_packages(
+                packages,
+                installed_packages,
+                &self.prefix,
+                self.repos.clone(),
+                &self.options,
+                &self.cache_dir,
+                &self.tmp_dir_prefix,
+            )
+        };
+        // repair records the stats of restored files
+        match self.options.repair {
+            true => self.modify(check),
+            false => self.scope(|| check(&mut self.installed_packages()?)),
         }
+    }
 
-        progress_bar.finish();
-        eprintln!();
+    /// Previews update of `packages`, all installed ones if empty.
+    pub fn preview_update(&self, packages: Vec<String>) -> anyhow::Result<Vec<UpdatePreview>> {
+        let installed_packages = self.installed_packages()?;
+        self.scope(|| {
+            preview_update_packages(
+                packages,
+                &installed_packages,
+                &self.prefix,
+                self.repos.clone(),
+                self.channel,
+                &self.options,
+                &self.cache_dir,
+            )
+        })
     }
 
-    Ok(ret)
+    pub fn list_installed(&self) -> anyhow::Result<Vec<ListedPackage>> {
+        Ok(list_installed(&self.installed_packages()?, self.channel))
+    }
+
+    pub fn list_available(&self, packages: Vec<String>) -> anyhow::Result<Vec<AvailablePackage>> {
+        self.scope(|| {
+            list_available(
+                packages,
+                self.repos.clone(),
+                self.options.offline,
+                self.options.unverified_ssl,
+            )
+        })
+    }
+
+    pub fn package_details(&self, package: &str) -> anyhow::Result<PackageDetails> {
+        self.scope(|| {
+            get_package_details(
+                package,
+                &self.installed_packages()?,
+                &self.prefix,
+                self.repos.clone(),
+                self.options.offline,
+                self.options.unverified_ssl,
+                &self.cache_dir,
+            )
+        })
+    }
+
+    pub fn package_files(&self, package: &str) -> anyhow::Result<Vec<PackageFile>> {
+        self.scope(|| {
+            get_package_files(
+                package,
+                &self.installed_packages()?,
+                self.repos.clone(),
+                self.options.offline,
+                self.options.unverified_ssl,
+                &self.cache_dir,
+            )
+        })
+    }
+
+    pub fn owners(&self, paths: Vec<String>) -> anyhow::Result<Vec<OwnedPath>> {
+        get_owners(paths, &self.installed_packages()?, &self.prefix)
+    }
+
+    pub fn untracked(&self, paths: Vec<String>) -> anyhow::Result<Vec<String>> {
+        get_untracked(paths, &self.installed_packages()?, &self.prefix)
+    }
+
+    pub fn history(&self) -> anyhow::Result<Vec<HistoryEntry>> {
+        get_history(&self.history_fn)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_as_unix_path() {
+        assert_eq!(as_unix_path(Path::new("/tmp/x")), "/tmp/x");
+        assert_eq!(as_unix_path(Path::new("tmp/x")), "tmp/x");
+    }
 
     #[test]
     fn test_get_md5_sum() {
@@ -1571,17 +5477,188 @@ mod tests {
     }
 
     #[test]
-    fn test_get_max_version() {
+    fn test_get_local_package_fn() {
+        let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+        let cache_dir_str = cache_dir.path().to_str().unwrap();
+        let url = "http://atxpkg.asterix.cz/test-1.0-1.atxpkg.zip";
+
+        assert_eq!(get_local_package_fn(url, cache_dir_str), None);
+        std::fs::write(format!("{cache_dir_str}/test-1.0-1.atxpkg.zip"), "").unwrap();
+        assert_eq!(
+            get_local_package_fn(url, cache_dir_str),
+            Some(format!("{cache_dir_str}/test-1.0-1.atxpkg.zip"))
+        );
+        assert_eq!(
+            get_local_package_fn("./test_data/test-1.0-1.atxpkg.zip", cache_dir_str),
+            Some("./test_data/test-1.0-1.atxpkg.zip".to_string())
+        );
+        assert_eq!(
+            get_local_package_fn("./test_data/missing-1.0-1.atxpkg.zip", cache_dir_str),
+            None
+        );
+    }
+
+    #[test]
+    fn test_get_max_version_url() {
         assert_eq!(
-            get_max_version(vec![
+            get_max_version_url(vec![
                 "http://atxpkg.asterix.cz/neco.dev-20240722223041-1.atxpkg.zip".to_string(),
                 "http://atxpkg-dev.asterix.cz/neco.dev-20240722223042-1.atxpkg.zip".to_string(),
                 "/neco/na/disku/neco.dev-20240722223043-1.atxpkg.zip".to_string(),
             ]),
-            Some("20240722223043-1".to_string())
+            Some("/neco/na/disku/neco.dev-20240722223043-1.atxpkg.zip".to_string())
+        )
+    }
+
+    #[test]
+    fn test_get_max_version_url_skips_invalid() {
+        assert_eq!(
+            get_max_version_url(vec![
+                "/repo/pkg-1.0-1.atxpkg.zip".to_string(),
+                "/repo/pkg-1.1rc1-1.atxpkg.zip".to_string(),
+                "/repo/pkg-x1.2-1.atxpkg.zip".to_string(),
+            ]),
+            Some("/repo/pkg-1.1rc1-1.atxpkg.zip".to_string())
         )
     }
 
+    #[test]
+    fn test_compare_versions() {
+        use std::cmp::Ordering::*;
+
+        assert_eq!(compare_versions("6.3-1", "6.3-1"), Equal);
+        assert_eq!(compare_versions("6.3-1", "6.03-1"), Equal);
+        assert_eq!(compare_versions("6.3-2", "6.3-1"), Greater);
+        assert_eq!(compare_versions("6.10-1", "6.9-1"), Greater);
+        assert_eq!(compare_versions("6.3.1-1", "6.3-1"), Greater);
+        assert_eq!(compare_versions("1.2rc1-1", "1.2-1"), Less);
+        assert_eq!(compare_versions("2.0-beta-1", "2.0-1"), Less);
+        assert_eq!(compare_versions("2.0-beta-1", "2.0-rc-1"), Less);
+        assert_eq!(compare_versions("1.0a", "1.0"), Less);
+        assert_eq!(compare_versions("1.0", "1.0.a"), Less);
+        assert_eq!(compare_versions("1.0.a", "1.0.1"), Less);
+        assert_eq!(compare_versions("1.0-1", "1.0.a-1"), Less);
+        assert_eq!(compare_versions("1.0.1-1", "1.0-2"), Greater);
+        assert_eq!(compare_versions("1.0.a-1", "1.0.1-1"), Less);
+        assert_eq!(compare_versions("1.0-10", "1.0-9"), Greater);
+        assert_eq!(compare_versions("1.0a-2", "1.0-1"), Less);
+        assert_eq!(compare_versions("2.0-rc-2", "2.0-1"), Less);
+        assert_eq!(compare_versions("1!1.0-1", "20240722223041-1"), Greater);
+        assert_eq!(
+            compare_versions("123456789012345678901234567890-1", "9-1"),
+            Greater
+        );
+        assert_eq!(compare_versions("invalid", "1.0-1"), Less);
+
+        // pre-releases compare by their label whichever way they are written
+        let v = |x: &str| x.parse::<Version>().unwrap();
+        assert_eq!(v("1.0-beta-1").cmp(&v("1.0rc1-1")), Less);
+        assert_eq!(v("1.0rc1-1").cmp(&v("1.0beta2-1")), Greater);
+        assert_eq!(v("1.0rc1-1").cmp(&v("1.0-1")), Less);
+    }
+
+    #[test]
+    fn test_split_package_name_version() {
+        let split = |x| {
+            let (name, version) = split_package_name_version(x);
+            (name.to_string(), version.to_string())
+        };
+        assert_eq!(split("test"), ("test".into(), "".into()));
+        assert_eq!(split("test-1.0-1"), ("test".into(), "1.0-1".into()));
+        assert_eq!(
+            split("atx300-base-6.3-1.atxpkg.zip"),
+            ("atx300-base".into(), "6.3-1".into())
+        );
+        assert_eq!(
+            split("atx-300-base-6.3-1"),
+            ("atx-300-base".into(), "6.3-1".into())
+        );
+        assert_eq!(
+            split("atx300-base.dev"),
+            ("atx300-base.dev".into(), "".into())
+        );
+        assert_eq!(split("pkg-2.0-beta-1"), ("pkg".into(), "2.0-beta-1".into()));
+        assert_eq!(split("pkg-1!2.0-1"), ("pkg".into(), "1!2.0-1".into()));
+    }
+
+    #[test]
+    fn test_parse_package_spec() {
+        let parse = |x| {
+            let (name, constraint) = parse_package_spec(x).unwrap();
+            (name, constraint.map(|c| c.to_string()))
+        };
+        assert_eq!(parse("test"), ("test".into(), None));
+        assert_eq!(parse("test-1.0-1"), ("test".into(), Some("-1.0-1".into())));
+        assert_eq!(
+            parse("atx300-base>=6.3"),
+            ("atx300-base".into(), Some(">=6.3".into()))
+        );
+        assert_eq!(
+            parse("atx300-base<7"),
+            ("atx300-base".into(), Some("<7".into()))
+        );
+        assert_eq!(
+            parse("atx300-base.dev~6.3"),
+            ("atx300-base.dev".into(), Some("~6.3".into()))
+        );
+        assert_eq!(parse("test==1.0-1"), ("test".into(), Some("=1.0-1".into())));
+        assert!(parse_package_spec("test>=").is_err());
+        assert!(parse_package_spec("test<x1").is_err());
+    }
+
+    #[test]
+    fn test_channel_package_name() {
+        assert_eq!(Channel::Dev.package_name("atx300-base"), "atx300-base.dev");
+        assert_eq!(
+            Channel::Dev.package_name("atx300-base.dev"),
+            "atx300-base.dev"
+        );
+        assert_eq!(
+            Channel::Stable.package_name("atx300-base.dev"),
+            "atx300-base"
+        );
+        assert_eq!(Channel::Stable.package_name("atx300-base"), "atx300-base");
+        assert_eq!("dev".parse::<Channel>().unwrap(), Channel::Dev);
+        assert!("beta".parse::<Channel>().is_err());
+    }
+
+    #[test]
+    fn test_version_constraint_matches() {
+        let matches = |spec, version| {
+            parse_package_spec(spec)
+                .unwrap()
+                .1
+                .unwrap()
+                .matches(version)
+        };
+
+        assert!(matches("test-6.3-1", "6.3-1"));
+        assert!(!matches("test-6.3-1", "6.3-2"));
+        assert!(matches("test=6.3", "6.3.2-1"));
+        assert!(!matches("test=6.3", "6.30-1"));
+        assert!(!matches("test=6.3", "6.3rc1-1"));
+        assert!(matches("test>=6.3", "6.3-1"));
+        assert!(matches("test>=6.3", "7.0-1"));
+        assert!(!matches("test>=6.3", "6.3rc1-1"));
+        assert!(!matches("test>=6.3", "6.2.9-1"));
+        assert!(matches("test>6.3", "6.4-1"));
+        assert!(!matches("test>6.3", "6.3.1-1"));
+        assert!(matches("test<7", "6.9-1"));
+        assert!(!matches("test<7", "7.0-1"));
+        assert!(matches("test<=6.3", "6.3.1-1"));
+        assert!(!matches("test<=6.3", "6.4-1"));
+        assert!(matches("test~6.3", "6.3-1"));
+        assert!(matches("test~6.3", "6.9-1"));
+        assert!(!matches("test~6.3", "6.2-1"));
+        assert!(!matches("test~6.3", "7.0-1"));
+        assert!(matches("test~6.3.1", "6.3.5-1"));
+        assert!(!matches("test~6.3.1", "6.4-1"));
+        assert!(matches("test<6.3-2", "6.3-1"));
+        assert!(!matches("test<6.3-2", "6.3.1-1"));
+        assert!(matches("test>=6.3-2", "6.3.1-1"));
+        assert!(!matches("test>=6.3", "invalid"));
+    }
+
     #[test]
     fn test_is_valid_package_fn() {
         // valid cases
@@ -1592,6 +5669,9 @@ mod tests {
         assert!(is_valid_package_fn("atxpkg-1.5-3.atxpkg.zip"));
         assert!(is_valid_package_fn("atx300-base-6.3-1.atxpkg.zip"));
         assert!(is_valid_package_fn("test-1.0-1.atxpkg.zip"));
+        assert!(is_valid_package_fn("pkg-1.2rc1-1.atxpkg.zip"));
+        assert!(is_valid_package_fn("pkg-2.0-beta-1.atxpkg.zip"));
+        assert!(is_valid_package_fn("pkg-1!2.0-1.atxpkg.zip"));
 
         // invalid cases
         assert!(!is_valid_package_fn("invalid.zip"));
@@ -1601,6 +5681,8 @@ mod tests {
         assert!(!is_valid_package_fn("package-1.0-1.zip"));
         assert!(!is_valid_package_fn(""));
         assert!(!is_valid_package_fn("package--1.atxpkg.zip"));
+        assert!(!is_valid_package_fn("package-beta-1.atxpkg.zip"));
+        assert!(!is_valid_package_fn("package-1.0-x.atxpkg.zip"));
     }
 
     #[test]
@@ -1635,8 +5717,10 @@ mod tests {
         let pkginfo = install_package(
             "./test_data/atx300-base-6.3-1.atxpkg.zip",
             &dest_dir_str,
+            &[],
             false,
             tmp_dir.path().to_str().unwrap(),
+            &mut Transaction::new(tmp_dir.path().to_str().unwrap(), None).unwrap(),
         )
         .unwrap();
 
@@ -1645,6 +5729,263 @@ mod tests {
         assert!(!Path::new(&format!("{dest_dir_str}/atx300/.atxpkg_backup")).exists());
     }
 
+    #[test]
+    fn test_parse_lines() {
+        assert_eq!(
+            parse_lines("# base system\r\natx300-base>=6.3\r\n\r\n  test-1.0-1  \n  # test\n"),
+            vec!["atx300-base>=6.3", "test-1.0-1"]
+        );
+    }
+
+    #[test]
+    fn test_planned_change() {
+        let change = PlannedChange {
+            action: PlanAction::Update,
+            name: "test.dev".to_string(),
+            version: "2.0-1".to_string(),
+            name_old: Some("test".to_string()),
+            version_old: Some("1.0-1".to_string()),
+            detail: None,
+        };
+        assert_eq!(change.to_string(), "update test-1.0-1 -> test.dev-2.0-1");
+        assert_eq!(
+            serde_json::to_value(&change).unwrap(),
+            serde_json::json!({
+                "action": "update",
+                "name": "test.dev",
+                "version": "2.0-1",
+                "name_old": "test",
+                "version_old": "1.0-1",
+                "detail": null,
+            })
+        );
+        let change = PlannedChange {
+            action: PlanAction::Install,
+            name_old: None,
+            version_old: None,
+            detail: None,
+            ..change
+        };
+        assert_eq!(change.to_string(), "install test.dev-2.0-1");
+        let change = PlannedChange {
+            action: PlanAction::Hold,
+            detail: Some("3.0".to_string()),
+            ..change
+        };
+        assert_eq!(change.to_string(), "hold test.dev-2.0-1 at <=3.0");
+        let change = PlannedChange {
+            action: PlanAction::SetChannel,
+            detail: None,
+            ..change
+        };
+        assert_eq!(
+            change.to_string(),
+            "set channel of test.dev-2.0-1 to global"
+        );
+    }
+
+    #[test]
+    fn test_reporter() {
+        #[derive(Default)]
+        struct TestReporter {
+            messages: std::sync::Mutex<Vec<String>>,
+            answer: AtomicBool,
+        }
+
+        impl Reporter for TestReporter {
+            fn message(&self, msg: &str) {
+                self.messages.lock().unwrap().push(msg.to_string());
+            }
+
+            fn confirm(&self, _prompt: &str, _default: bool) -> anyhow::Result<bool> {
+                Ok(self.answer.load(Ordering::Relaxed))
+            }
+        }
+
+        // silent reporter declines
+        assert!(!SilentReporter.confirm("continue?", true).unwrap());
+
+        // scoped to this thread so that tests running in parallel don't interfere
+        let reporter = Arc::new(TestReporter::default());
+        let session = SessionScope {
+            reporter: reporter.clone(),
+            command: "atxpkg test".to_string(),
+        };
+        in_session(&session, || {
+            assert_eq!(command(), "atxpkg test");
+            status!("{}-{} is now installed", "test", "1.0-1");
+            assert_eq!(
+                *reporter.messages.lock().unwrap(),
+                vec!["test-1.0-1 is now installed".to_string()]
+            );
+            assert!(confirm(true, false, false).unwrap());
+            assert!(!confirm(false, true, true).unwrap());
+            assert!(matches!(
+                confirm(false, false, true)
+                    .unwrap_err()
+                    .downcast_ref::<Error>(),
+                Some(Error::Declined)
+            ));
+            reporter.answer.store(true, Ordering::Relaxed);
+            assert!(confirm(false, false, false).unwrap());
+        });
+        // outside of the scope messages don't reach it
+        status!("test-1.0-1 is now removed");
+        assert_eq!(reporter.messages.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_save_installed_packages_backups() {
+        let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+        let db_fn = format!("{}/installed.json", tmp_dir.path().to_str().unwrap());
+        let mut installed_packages = HashMap::new();
+        for version in ["1.0-1", "2.0-1", "3.0-1"] {
+            installed_packages.insert(
+                "test".to_string(),
+                InstalledPackage {
+                    t: None,
+                    version: version.to_string(),
+                    md5sums: HashMap::new(),
+                    backup: None,
+                    repo: None,
+                    hold: None,
+                    channel: None,
+                    previous: None,
+                    file_stats: None,
+                },
+            );
+            save_installed_packages(&installed_packages, &db_fn).unwrap();
+        }
+        // saving the same content doesn't rotate the backups
+        save_installed_packages(&installed_packages, &db_fn).unwrap();
+        assert_eq!(get_installed_packages(&db_fn).unwrap(), installed_packages);
+        assert!(Path::new(&format!("{db_fn}.2")).exists());
+        assert!(!Path::new(&format!("{db_fn}.3")).exists());
+        assert!(!Path::new(&format!("{db_fn}.tmp")).exists());
+        // unset fields are left out so that older versions can read the database
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&std::fs::read_to_string(&db_fn).unwrap())
+                .unwrap(),
+            serde_json::json!({
+                "test": {"t": null, "version": "3.0-1", "md5sums": {}, "backup": null}
+            })
+        );
+
+        std::fs::write(&db_fn, "{\"test\": {").unwrap();
+        assert_eq!(
+            get_installed_packages(&db_fn).unwrap()["test"].version,
+            "2.0-1"
+        );
+
+        std::fs::write(format!("{db_fn}.1"), "").unwrap();
+        assert_eq!(
+            get_installed_packages(&db_fn).unwrap()["test"].version,
+            "1.0-1"
+        );
+
+        std::fs::remove_file(format!("{db_fn}.2")).unwrap();
+        assert!(get_installed_packages(&db_fn).is_err());
+    }
+
+    #[test]
+    fn test_recover_interrupted_transaction() {
+        let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+        let dest_dir_str = dest_dir.path().to_str().unwrap();
+        let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+        let tmp_dir_str = tmp_dir.path().to_str().unwrap();
+        let installed_packages_orig = HashMap::from([(
+            "test".to_string(),
+            InstalledPackage {
+                t: None,
+                version: "1.0-1".to_string(),
+                md5sums: HashMap::new(),
+                backup: None,
+                repo: None,
+                hold: None,
+                channel: None,
+                previous: None,
+                file_stats: None,
+            },
+        )]);
+        std::fs::write(format!("{dest_dir_str}/old"), "old\n").unwrap();
+        std::fs::write(format!("{tmp_dir_str}/new"), "new\n").unwrap();
+
+        let mut transaction =
+            Transaction::new(tmp_dir_str, Some(&installed_packages_orig)).unwrap();
+        transaction
+            .create_dir(&format!("{dest_dir_str}/dir"))
+            .unwrap();
+        transaction
+            .put_file(
+                &format!("{tmp_dir_str}/new"),
+                &format!("{dest_dir_str}/old"),
+            )
+            .unwrap();
+        // simulate crash - nothing gets cleaned up
+        std::mem::forget(transaction);
+
+        assert!(Transaction::new(tmp_dir_str, None).is_err());
+
+        let mut installed_packages = HashMap::new();
+        assert!(recover(&mut installed_packages, tmp_dir_str).unwrap());
+        assert_eq!(installed_packages, installed_packages_orig);
+        assert!(!Path::new(&format!("{dest_dir_str}/dir")).exists());
+        assert_eq!(
+            std::fs::read_to_string(format!("{dest_dir_str}/old")).unwrap(),
+            "old\n"
+        );
+        assert!(find_journals(tmp_dir_str).unwrap().is_empty());
+        assert!(!recover(&mut installed_packages, tmp_dir_str).unwrap());
+    }
+
+    #[test]
+    fn test_recover_committed_transaction() {
+        let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+        let tmp_dir_str = tmp_dir.path().to_str().unwrap();
+        let mut installed_packages = HashMap::new();
+        let mut installed_packages_new = HashMap::new();
+        installed_packages_new.insert(
+            "test".to_string(),
+            InstalledPackage {
+                t: None,
+                version: "1.0-1".to_string(),
+                md5sums: HashMap::new(),
+                backup: None,
+                repo: None,
+                hold: None,
+                channel: None,
+                previous: None,
+                file_stats: None,
+            },
+        );
+
+        let transaction = Transaction::new(tmp_dir_str, Some(&installed_packages)).unwrap();
+        transaction.commit(Some(&installed_packages_new)).unwrap();
+        // journal of a committed transaction is kept until the database is known to be saved
+        let transaction = Transaction::new(tmp_dir_str, Some(&installed_packages)).unwrap();
+        drop(transaction);
+        assert_eq!(find_journals(tmp_dir_str).unwrap().len(), 1);
+        assert!(needs_recover(tmp_dir_str, &installed_packages).unwrap());
+        assert!(!needs_recover(tmp_dir_str, &installed_packages_new).unwrap());
+        remove_finished_journals(tmp_dir_str, &installed_packages).unwrap();
+        assert_eq!(find_journals(tmp_dir_str).unwrap().len(), 1);
+
+        // database wasn't saved before the crash
+        assert!(recover(&mut installed_packages, tmp_dir_str).unwrap());
+        assert_eq!(installed_packages, installed_packages_new);
+        assert!(find_journals(tmp_dir_str).unwrap().is_empty());
+
+        // saved database changed after the commit is left alone
+        let transaction = Transaction::new(tmp_dir_str, Some(&installed_packages)).unwrap();
+        transaction.commit(Some(&HashMap::new())).unwrap();
+        installed_packages.get_mut("test").unwrap().hold = Some(Hold { max_version: None });
+        let installed_packages_saved = installed_packages.clone();
+        assert!(!recover(&mut installed_packages, tmp_dir_str).unwrap());
+        assert_eq!(installed_packages, installed_packages_saved);
+        remove_finished_journals(tmp_dir_str, &installed_packages).unwrap();
+        assert!(find_journals(tmp_dir_str).unwrap().is_empty());
+    }
+
     #[test]
     fn test_install_update_package_with_conflict() {
         let dest_dir = tempfile::Builder::new().tempdir().unwrap();
@@ -1654,8 +5995,10 @@ mod tests {
         let pkginfo = install_package(
             "./test_data/test-1.0-1.atxpkg.zip",
             dest_dir_str,
+            &[],
             false,
             tmp_dir.path().to_str().unwrap(),
+            &mut Transaction::new(tmp_dir.path().to_str().unwrap(), None).unwrap(),
         )
         .unwrap();
 
@@ -1668,10 +6011,89 @@ mod tests {
             "test",
             pkginfo,
             dest_dir_str,
+            &[],
+            tmp_dir.path().to_str().unwrap(),
+        );
+        assert!(pkginfo.is_err());
+    }
+
+    #[test]
+    fn test_install_package_with_overwrite() {
+        let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+        let dest_dir_str = dest_dir.path().to_str().unwrap();
+        let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+
+        std::fs::create_dir(format!("{dest_dir_str}/test")).unwrap();
+        std::fs::write(format!("{dest_dir_str}/test/protected1"), "x\n").unwrap();
+        std::fs::write(format!("{dest_dir_str}/test/unprotected"), "x\n").unwrap();
+
+        let pkginfo = install_package(
+            "./test_data/test-1.0-1.atxpkg.zip",
+            dest_dir_str,
+            &["test/unprotected".to_string()],
             false,
             tmp_dir.path().to_str().unwrap(),
+            &mut Transaction::new(tmp_dir.path().to_str().unwrap(), None).unwrap(),
         );
         assert!(pkginfo.is_err());
+
+        let pkginfo = install_package(
+            "./test_data/test-1.0-1.atxpkg.zip",
+            dest_dir_str,
+            &["test/*".to_string()],
+            false,
+            tmp_dir.path().to_str().unwrap(),
+            &mut Transaction::new(tmp_dir.path().to_str().unwrap(), None).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(pkginfo.version, "1.0-1");
+        assert!(Path::new(&format!("{dest_dir_str}/test/protected1.atxpkg_save")).exists());
+        assert!(!Path::new(&format!("{dest_dir_str}/test/unprotected.atxpkg_save")).exists());
+        assert_eq!(
+            std::fs::read_to_string(format!("{dest_dir_str}/test/unprotected")).unwrap(),
+            "1\n"
+        );
+    }
+
+    #[test]
+    fn test_install_package_with_adopt() {
+        let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+        let dest_dir_str = dest_dir.path().to_str().unwrap();
+        let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+
+        std::fs::create_dir(format!("{dest_dir_str}/test")).unwrap();
+        std::fs::write(format!("{dest_dir_str}/test/protected1"), "x\n").unwrap();
+        std::fs::write(format!("{dest_dir_str}/test/protected2"), "1\n").unwrap();
+        std::fs::write(format!("{dest_dir_str}/test/unprotected"), "x\n").unwrap();
+
+        let pkginfo = install_package(
+            "./test_data/test-1.0-1.atxpkg.zip",
+            dest_dir_str,
+            &[],
+            true,
+            tmp_dir.path().to_str().unwrap(),
+            &mut Transaction::new(tmp_dir.path().to_str().unwrap(), None).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(pkginfo.version, "1.0-1");
+        assert!(pkginfo.md5sums.contains_key("test/protected1"));
+        assert!(pkginfo.md5sums.contains_key("test/unprotected"));
+        assert_eq!(
+            std::fs::read_to_string(format!("{dest_dir_str}/test/protected1")).unwrap(),
+            "x\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(format!("{dest_dir_str}/test/protected1.atxpkg_new")).unwrap(),
+            "1\n"
+        );
+        assert!(!Path::new(&format!("{dest_dir_str}/test/protected2.atxpkg_new")).exists());
+        assert_eq!(
+            std::fs::read_to_string(format!("{dest_dir_str}/test/unprotected")).unwrap(),
+            "x\n"
+        );
+        assert!(Path::new(&format!("{dest_dir_str}/test/protected3")).exists());
     }
 
     #[test]
@@ -1683,8 +6105,10 @@ mod tests {
         let pkginfo = install_package(
             "./test_data/test-1.0-1.atxpkg.zip",
             dest_dir_str,
+            &[],
             false,
             tmp_dir.path().to_str().unwrap(),
+            &mut Transaction::new(tmp_dir.path().to_str().unwrap(), None).unwrap(),
         )
         .unwrap();
 
@@ -1699,7 +6123,7 @@ mod tests {
             "test",
             pkginfo,
             dest_dir_str,
-            false,
+            &[],
             tmp_dir.path().to_str().unwrap(),
         )
         .unwrap();
@@ -1721,8 +6145,10 @@ mod tests {
         let pkginfo = install_package(
             "./test_data/test-1.0-1.atxpkg.zip",
             dest_dir_str,
+            &[],
             false,
             tmp_dir.path().to_str().unwrap(),
+            &mut Transaction::new(tmp_dir.path().to_str().unwrap(), None).unwrap(),
         )
         .unwrap();
 
@@ -1732,7 +6158,13 @@ mod tests {
         std::fs::write(format!("{dest_dir_str}/test/protected2"), "2\n").unwrap();
         std::fs::write(format!("{dest_dir_str}/test/unprotected2"), "2\n").unwrap();
 
-        remove_package("test", pkginfo, dest_dir_str).unwrap();
+        remove_package(
+            "test",
+            pkginfo,
+            dest_dir_str,
+            tmp_dir.path().to_str().unwrap(),
+        )
+        .unwrap();
 
         assert!(Path::new(&format!("{dest_dir_str}/test/protected1.atxpkg_backup")).exists());
         assert!(Path::new(&format!("{dest_dir_str}/test/protected2.atxpkg_backup")).exists());
@@ -1740,6 +6172,85 @@ mod tests {
         assert!(!Path::new(&format!("{dest_dir_str}/test/unprotected.atxpkg_backup")).exists());
     }
 
+    #[test]
+    fn test_get_owners() {
+        let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+        let dest_dir_str = dest_dir.path().to_str().unwrap();
+        let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+
+        let pkginfo = install_package(
+            "./test_data/test-1.0-1.atxpkg.zip",
+            dest_dir_str,
+            &[],
+            false,
+            tmp_dir.path().to_str().unwrap(),
+            &mut Transaction::new(tmp_dir.path().to_str().unwrap(), None).unwrap(),
+        )
+        .unwrap();
+
+        let mut installed_packages = HashMap::new();
+        installed_packages.insert("test".to_string(), pkginfo);
+
+        std::fs::write(format!("{dest_dir_str}/test/protected1"), "x\n").unwrap();
+        std::fs::remove_file(format!("{dest_dir_str}/test/unprotected")).unwrap();
+        std::fs::remove_file(format!("{dest_dir_str}/test/protected3")).unwrap();
+        std::fs::create_dir(format!("{dest_dir_str}/test/protected3")).unwrap();
+
+        let owners = get_owners(
+            vec![
+                "test/protected1".to_string(),
+                format!("{dest_dir_str}/test/protected2"),
+                "test/protected3".to_string(),
+                "test/unprotected".to_string(),
+                "test/empty".to_string(),
+                "test/unknown".to_string(),
+            ],
+            &installed_packages,
+            dest_dir_str,
+        )
+        .unwrap();
+
+        let owner = |is_dir, is_backup, is_modified, is_missing| {
+            Some(PathOwner {
+                package_name: "test".to_string(),
+                version: "1.0-1".to_string(),
+                is_dir,
+                is_backup,
+                is_modified,
+                is_missing,
+            })
+        };
+        assert_eq!(
+            owners,
+            vec![
+                OwnedPath {
+                    path: "test/protected1".to_string(),
+                    owner: owner(false, true, true, false)
+                },
+                OwnedPath {
+                    path: "test/protected2".to_string(),
+                    owner: owner(false, true, false, false)
+                },
+                OwnedPath {
+                    path: "test/protected3".to_string(),
+                    owner: owner(false, true, true, false)
+                },
+                OwnedPath {
+                    path: "test/unprotected".to_string(),
+                    owner: owner(false, false, false, true)
+                },
+                OwnedPath {
+                    path: "test/empty".to_string(),
+                    owner: owner(true, false, false, false)
+                },
+                OwnedPath {
+                    path: "test/unknown".to_string(),
+                    owner: None
+                },
+            ]
+        );
+    }
+
     #[test]
     fn test_get_untracked() {
         let dest_dir = tempfile::Builder::new().tempdir().unwrap();
@@ -1749,8 +6260,10 @@ mod tests {
         let pkginfo = install_package(
             "./test_data/test-1.0-1.atxpkg.zip",
             dest_dir_str,
+            &[],
             false,
             tmp_dir.path().to_str().unwrap(),
+            &mut Transaction::new(tmp_dir.path().to_str().unwrap(), None).unwrap(),
         )
         .unwrap();
 
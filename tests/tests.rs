@@ -8,13 +8,8 @@ fn test_list_available() {
     let repos = vec!["./test_data".to_string()];
     let avail = list_available(packages, repos, false, false).unwrap();
     assert_eq!(
-        avail,
-        vec![
-            ("atx300-base".to_string(), "".to_string()),
-            ("atx300-base.dev".to_string(), "".to_string()),
-            ("atxpkg".to_string(), "".to_string()),
-            ("test".to_string(), "".to_string()),
-        ]
+        avail.into_iter().map(|x| x.name).collect::<Vec<_>>(),
+        vec!["atx300-base", "atx300-base.dev", "atxpkg", "test"]
     );
 }
 
@@ -44,14 +39,14 @@ fn test_install_packages() {
         &mut installed_packages,
         dest_dir_str,
         vec!["./test_data".to_string()],
-        false,
-        false,
-        true,
-        false,
-        false,
-        false,
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
         cache_dir.path().to_str().unwrap(),
         tmp_dir.path().to_str().unwrap(),
+        None,
     )
     .unwrap();
 
@@ -76,9 +71,14 @@ fn test_update_package() {
             version: "6.3-1".to_string(),
             md5sums: HashMap::new(),
             backup: Some(Vec::new()),
+            repo: None,
+            hold: None,
+            channel: None,
+            previous: None,
+            file_stats: None,
         },
         dest_dir_str,
-        false,
+        &[],
         tmp_dir.path().to_str().unwrap(),
     )
     .unwrap();
@@ -101,14 +101,14 @@ fn test_update_packages() {
         &mut installed_packages,
         dest_dir_str,
         vec!["./test_data".to_string()],
-        false,
-        false,
-        true,
-        false,
-        false,
-        false,
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
         cache_dir.path().to_str().unwrap(),
         tmp_dir.path().to_str().unwrap(),
+        None,
     )
     .unwrap();
 
@@ -130,14 +130,14 @@ fn test_update_packages() {
         &mut installed_packages,
         dest_dir_str,
         vec!["./test_data".to_string()],
-        false,
-        false,
-        true,
-        false,
-        false,
-        false,
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
         cache_dir.path().to_str().unwrap(),
         tmp_dir.path().to_str().unwrap(),
+        None,
     )
     .unwrap();
 
@@ -164,14 +164,14 @@ fn test_remove_packages() {
         &mut installed_packages,
         dest_dir_str,
         vec!["./test_data".to_string()],
-        false,
-        false,
-        true,
-        false,
-        false,
-        false,
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
         cache_dir.path().to_str().unwrap(),
         tmp_dir.path().to_str().unwrap(),
+        None,
     )
     .unwrap();
 
@@ -186,8 +186,12 @@ fn test_remove_packages() {
         vec!["atx300-base".to_string(), "test".to_string()],
         &mut installed_packages,
         dest_dir_str,
-        true,
-        false,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        tmp_dir.path().to_str().unwrap(),
+        None,
     )
     .unwrap();
 
@@ -218,14 +222,14 @@ fn test_upstall_packages_install_only() {
         &mut installed_packages,
         dest_dir_str,
         vec!["./test_data".to_string()],
-        false,
-        false,
-        true,
-        false,
-        false,
-        false,
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
         cache_dir.path().to_str().unwrap(),
         tmp_dir.path().to_str().unwrap(),
+        None,
     )
     .unwrap();
 
@@ -254,14 +258,14 @@ fn test_upstall_packages_update_only() {
         &mut installed_packages,
         dest_dir_str,
         vec!["./test_data".to_string()],
-        false,
-        false,
-        true,
-        false,
-        false,
-        false,
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
         cache_dir.path().to_str().unwrap(),
         tmp_dir.path().to_str().unwrap(),
+        None,
     )
     .unwrap();
 
@@ -274,14 +278,14 @@ fn test_upstall_packages_update_only() {
         &mut installed_packages,
         dest_dir_str,
         vec!["./test_data".to_string()],
-        false,
-        false,
-        true,
-        false,
-        false,
-        false,
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
         cache_dir.path().to_str().unwrap(),
         tmp_dir.path().to_str().unwrap(),
+        None,
     )
     .unwrap();
 
@@ -304,14 +308,14 @@ fn test_upstall_packages_mixed() {
         &mut installed_packages,
         dest_dir_str,
         vec!["./test_data".to_string()],
-        false,
-        false,
-        true,
-        false,
-        false,
-        false,
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
         cache_dir.path().to_str().unwrap(),
         tmp_dir.path().to_str().unwrap(),
+        None,
     )
     .unwrap();
 
@@ -325,14 +329,14 @@ fn test_upstall_packages_mixed() {
         &mut installed_packages,
         dest_dir_str,
         vec!["./test_data".to_string()],
-        false,
-        false,
-        true,
-        false,
-        false,
-        false,
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
         cache_dir.path().to_str().unwrap(),
         tmp_dir.path().to_str().unwrap(),
+        None,
     )
     .unwrap();
 
@@ -361,14 +365,14 @@ fn test_upstall_packages_no_change() {
         &mut installed_packages,
         dest_dir_str,
         vec!["./test_data".to_string()],
-        false,
-        false,
-        true,
-        false,
-        false,
-        false,
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
         cache_dir.path().to_str().unwrap(),
         tmp_dir.path().to_str().unwrap(),
+        None,
     )
     .unwrap();
 
@@ -381,20 +385,1856 @@ fn test_upstall_packages_no_change() {
         &mut installed_packages,
         dest_dir_str,
         vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+
+    // Should return false as no operation occurred
+    assert!(!result);
+
+    let pkginfo = installed_packages.get("test").unwrap();
+    assert_eq!(pkginfo.version, "2.0-1");
+}
+
+#[test]
+fn test_install_packages_reinstall() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+
+    let mut installed_packages = HashMap::default();
+    atxpkg::install_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+
+    std::fs::remove_file(format!("{dest_dir_str}/test/unprotected")).unwrap();
+    std::fs::write(format!("{dest_dir_str}/test/untracked"), "x\n").unwrap();
+
+    // already installed and reinstall not requested
+    let result = atxpkg::install_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    );
+    assert!(result.is_err());
+
+    let result = atxpkg::install_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            reinstall: true,
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert!(result);
+
+    let pkginfo = installed_packages.get("test").unwrap();
+    assert_eq!(pkginfo.version, "2.0-1");
+    assert!(Path::new(&format!("{dest_dir_str}/test/unprotected")).exists());
+    assert!(Path::new(&format!("{dest_dir_str}/test/untracked")).exists());
+
+    // same version update is a no-op unless reinstall is requested
+    let result = atxpkg::update_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert!(!result);
+
+    let result = atxpkg::update_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            reinstall: true,
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert!(result);
+}
+
+#[test]
+fn test_get_package_details() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+
+    let mut installed_packages = HashMap::default();
+
+    let details = atxpkg::get_package_details(
+        "test-1.0-1",
+        &installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        false,
+        false,
+        cache_dir.path().to_str().unwrap(),
+    )
+    .unwrap();
+    assert!(!details.installed);
+    assert_eq!(details.version, "1.0-1");
+    assert_eq!(details.files, 4);
+    assert_eq!(details.dirs, 2);
+    assert_eq!(details.size, 8);
+    assert_eq!(
+        details.backup,
+        vec!["test/protected1", "test/protected2", "test/protected3"]
+    );
+    assert_eq!(details.repo, Some("./test_data".to_string()));
+    assert_eq!(details.newer_versions, vec!["2.0-1"]);
+
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+
+    let details = atxpkg::get_package_details(
+        "test",
+        &installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        false,
+        false,
+        cache_dir.path().to_str().unwrap(),
+    )
+    .unwrap();
+    assert!(details.installed);
+    assert!(details.t.is_some());
+    assert_eq!(details.version, "1.0-1");
+    assert_eq!(details.files, 4);
+    assert_eq!(details.dirs, 2);
+    assert_eq!(details.size, 8);
+    assert_eq!(details.repo, Some("./test_data".to_string()));
+    assert_eq!(details.newer_versions, vec!["2.0-1"]);
+
+    assert!(
+        atxpkg::get_package_details(
+            "nonexistent",
+            &installed_packages,
+            dest_dir_str,
+            vec!["./test_data".to_string()],
+            false,
+            false,
+            cache_dir.path().to_str().unwrap(),
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn test_get_package_files() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+
+    let mut installed_packages = HashMap::default();
+
+    let file = |path: &str, is_dir, is_backup| PackageFile {
+        path: path.to_string(),
+        is_dir,
+        is_backup,
+    };
+    let expected = vec![
+        file("test", true, false),
+        file("test/empty", true, false),
+        file("test/protected1", false, true),
+        file("test/protected2", false, true),
+        file("test/protected3", false, true),
+        file("test/unprotected", false, false),
+    ];
+
+    let files = atxpkg::get_package_files(
+        "./test_data/test-1.0-1.atxpkg.zip",
+        &installed_packages,
+        vec![],
+        false,
         false,
+        cache_dir.path().to_str().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(files, expected);
+
+    let files = atxpkg::get_package_files(
+        "test-1.0-1",
+        &installed_packages,
+        vec!["./test_data".to_string()],
         false,
-        true,
         false,
+        cache_dir.path().to_str().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(files, expected);
+
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+
+    let files = atxpkg::get_package_files(
+        "test",
+        &installed_packages,
+        vec![],
         false,
         false,
         cache_dir.path().to_str().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(files, expected);
+}
+
+#[test]
+fn test_preview_update_packages() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+
+    let mut installed_packages = HashMap::default();
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
         tmp_dir.path().to_str().unwrap(),
+        None,
     )
     .unwrap();
 
-    // Should return false as no operation occurred
-    assert!(!result);
+    std::fs::write(format!("{dest_dir_str}/test/protected1"), "x\n").unwrap();
+    std::fs::write(format!("{dest_dir_str}/test/new"), "x\n").unwrap();
 
-    let pkginfo = installed_packages.get("test").unwrap();
-    assert_eq!(pkginfo.version, "2.0-1");
+    let previews = atxpkg::preview_update_packages(
+        vec!["test".to_string()],
+        &installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options::default(),
+        cache_dir.path().to_str().unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(previews.len(), 1);
+    assert_eq!(previews[0].version_old, "1.0-1");
+    assert_eq!(previews[0].version_new, "2.0-1");
+    assert_eq!(
+        previews[0].files,
+        vec![
+            ("test/new".to_string(), FileAction::Conflict),
+            ("test/protected1".to_string(), FileAction::New),
+            ("test/protected2".to_string(), FileAction::Replace),
+            ("test/protected3".to_string(), FileAction::Replace),
+            ("test/unprotected".to_string(), FileAction::Replace),
+        ]
+    );
+
+    let previews = atxpkg::preview_update_packages(
+        vec!["test".to_string()],
+        &installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            overwrite: vec!["test/new".to_string()],
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        previews[0].files[0],
+        ("test/new".to_string(), FileAction::Overwrite)
+    );
+
+    // nothing was touched
+    assert_eq!(
+        std::fs::read_to_string(format!("{dest_dir_str}/test/protected1")).unwrap(),
+        "x\n"
+    );
+    assert!(!Path::new(&format!("{dest_dir_str}/test/protected1.atxpkg_new")).exists());
+    assert_eq!(installed_packages["test"].version, "1.0-1");
+
+    // downgrade removes the file which is not in the older version
+    std::fs::remove_file(format!("{dest_dir_str}/test/new")).unwrap();
+    atxpkg::update_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+
+    let previews = atxpkg::preview_update_packages(
+        vec!["test-1.0-1".to_string()],
+        &installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options::default(),
+        cache_dir.path().to_str().unwrap(),
+    )
+    .unwrap();
+    assert!(
+        previews[0]
+            .files
+            .contains(&("test/new".to_string(), FileAction::Remove))
+    );
+}
+
+#[test]
+fn test_install_update_packages_with_constraint() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+
+    let mut installed_packages = HashMap::default();
+    let result = atxpkg::install_packages(
+        vec!["test>2".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    );
+    assert!(result.is_err());
+    assert!(installed_packages.is_empty());
+
+    atxpkg::install_packages(
+        vec!["test<2".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(installed_packages["test"].version, "1.0-1");
+
+    assert!(atxpkg::if_installed(vec!["test~1.0".to_string()], &installed_packages).is_ok());
+    assert!(atxpkg::if_installed(vec!["test>=2".to_string()], &installed_packages).is_err());
+
+    atxpkg::update_packages(
+        vec!["test>=2.0".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(installed_packages["test"].version, "2.0-1");
+    assert!(atxpkg::if_installed(vec!["test>=2".to_string()], &installed_packages).is_ok());
+}
+
+#[test]
+fn test_update_packages_held() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+
+    let mut installed_packages = HashMap::default();
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+
+    assert!(
+        atxpkg::hold_packages(
+            vec!["nonexistent".to_string()],
+            &mut installed_packages,
+            None
+        )
+        .is_err()
+    );
+    assert!(atxpkg::unhold_packages(vec!["test".to_string()], &mut installed_packages).is_err());
+
+    let update = |installed_packages: &mut HashMap<String, InstalledPackage>, ignore_hold| {
+        atxpkg::update_packages(
+            vec!["test".to_string()],
+            installed_packages,
+            dest_dir_str,
+            vec!["./test_data".to_string()],
+            None,
+            &Options {
+                ignore_hold,
+                yes: true,
+                ..Default::default()
+            },
+            cache_dir.path().to_str().unwrap(),
+            tmp_dir.path().to_str().unwrap(),
+            None,
+        )
+        .unwrap()
+    };
+
+    atxpkg::hold_packages(vec!["test".to_string()], &mut installed_packages, None).unwrap();
+    assert!(!update(&mut installed_packages, false));
+    assert_eq!(installed_packages["test"].version, "1.0-1");
+
+    atxpkg::hold_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        Some("1.5".to_string()),
+    )
+    .unwrap();
+    assert!(!update(&mut installed_packages, false));
+    assert_eq!(installed_packages["test"].version, "1.0-1");
+
+    atxpkg::hold_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        Some("2.0".to_string()),
+    )
+    .unwrap();
+    assert!(update(&mut installed_packages, false));
+    assert_eq!(installed_packages["test"].version, "2.0-1");
+    assert!(installed_packages["test"].hold.is_some());
+
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            reinstall: true,
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    atxpkg::hold_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        Some("1.5".to_string()),
+    )
+    .unwrap();
+    assert!(update(&mut installed_packages, true));
+    assert_eq!(installed_packages["test"].version, "2.0-1");
+
+    // hold at a lower version must not downgrade the package
+    assert!(!update(&mut installed_packages, false));
+    assert_eq!(installed_packages["test"].version, "2.0-1");
+    assert!(
+        atxpkg::hold_packages(
+            vec!["test".to_string()],
+            &mut installed_packages,
+            Some("1.5".to_string()),
+        )
+        .is_err()
+    );
+    atxpkg::hold_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        Some("2.0".to_string()),
+    )
+    .unwrap();
+
+    // holds may only be ignored for explicitly named packages
+    assert!(
+        atxpkg::update_packages(
+            vec![],
+            &mut installed_packages,
+            dest_dir_str,
+            vec!["./test_data".to_string()],
+            None,
+            &Options {
+                ignore_hold: true,
+                yes: true,
+                ..Default::default()
+            },
+            cache_dir.path().to_str().unwrap(),
+            tmp_dir.path().to_str().unwrap(),
+            None
+        )
+        .is_err()
+    );
+
+    atxpkg::unhold_packages(vec!["test".to_string()], &mut installed_packages).unwrap();
+    assert!(installed_packages["test"].hold.is_none());
+}
+
+#[test]
+fn test_switch_channel() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+    let repo_dir = tempfile::Builder::new().tempdir().unwrap();
+    let repo_dir_str = repo_dir.path().to_str().unwrap();
+    std::fs::copy(
+        "./test_data/test-1.0-1.atxpkg.zip",
+        format!("{repo_dir_str}/test-1.0-1.atxpkg.zip"),
+    )
+    .unwrap();
+    std::fs::copy(
+        "./test_data/test-2.0-1.atxpkg.zip",
+        format!("{repo_dir_str}/test.dev-20240101-1.atxpkg.zip"),
+    )
+    .unwrap();
+
+    let mut installed_packages = HashMap::default();
+    atxpkg::install_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec![repo_dir_str.to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(installed_packages["test"].version, "1.0-1");
+
+    // global channel makes update follow the dev package
+    atxpkg::update_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec![repo_dir_str.to_string()],
+        Some(Channel::Dev),
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert!(!installed_packages.contains_key("test"));
+    assert_eq!(installed_packages["test.dev"].version, "20240101-1");
+
+    let switch = |installed_packages: &mut HashMap<String, InstalledPackage>, ignore_hold| {
+        atxpkg::switch_channel(
+            vec!["test.dev".to_string()],
+            installed_packages,
+            Channel::Stable,
+            dest_dir_str,
+            vec![repo_dir_str.to_string()],
+            &Options {
+                ignore_hold,
+                yes: true,
+                ..Default::default()
+            },
+            cache_dir.path().to_str().unwrap(),
+            tmp_dir.path().to_str().unwrap(),
+            None,
+        )
+    };
+
+    // held packages are only switched when holds are ignored
+    atxpkg::hold_packages(vec!["test.dev".to_string()], &mut installed_packages, None).unwrap();
+    assert!(switch(&mut installed_packages, false).is_err());
+    assert_eq!(installed_packages["test.dev"].version, "20240101-1");
+    assert!(switch(&mut installed_packages, true).unwrap());
+    atxpkg::unhold_packages(vec!["test".to_string()], &mut installed_packages).unwrap();
+    assert!(!installed_packages.contains_key("test.dev"));
+    assert_eq!(installed_packages["test"].version, "1.0-1");
+    assert_eq!(installed_packages["test"].channel, Some(Channel::Stable));
+
+    // per-package channel takes precedence over the global one
+    let res = atxpkg::update_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec![repo_dir_str.to_string()],
+        Some(Channel::Dev),
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert!(!res);
+    assert!(installed_packages.contains_key("test"));
+}
+
+#[test]
+fn test_rollback_packages() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir_str = cache_dir.path().to_str().unwrap();
+    let repo_dir = tempfile::Builder::new().tempdir().unwrap();
+    let repo_dir_str = repo_dir.path().to_str().unwrap();
+    for fn_ in ["test-1.0-1.atxpkg.zip", "test-2.0-1.atxpkg.zip"] {
+        std::fs::copy(
+            format!("./test_data/{fn_}"),
+            format!("{repo_dir_str}/{fn_}"),
+        )
+        .unwrap();
+    }
+
+    let mut installed_packages = HashMap::default();
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec![repo_dir_str.to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert!(installed_packages["test"].previous.is_none());
+    assert!(
+        atxpkg::rollback_packages(
+            vec!["test".to_string()],
+            &mut installed_packages,
+            dest_dir_str,
+            vec![repo_dir_str.to_string()],
+            &Options {
+                yes: true,
+                ..Default::default()
+            },
+            cache_dir_str,
+            tmp_dir.path().to_str().unwrap(),
+            None
+        )
+        .is_err()
+    );
+
+    // download only does not touch the rollback dir
+    atxpkg::update_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec![repo_dir_str.to_string()],
+        None,
+        &Options {
+            download_only: true,
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(installed_packages["test"].version, "1.0-1");
+    assert!(!Path::new(&format!("{cache_dir_str}/rollback")).exists());
+
+    atxpkg::update_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec![repo_dir_str.to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(installed_packages["test"].version, "2.0-1");
+    assert_eq!(
+        installed_packages["test"].previous,
+        Some(PreviousPackage {
+            name: "test".to_string(),
+            version: "1.0-1".to_string()
+        })
+    );
+
+    // the old package is gone from the repo and the cache is cleaned, rollback still works offline
+    std::fs::remove_file(format!("{repo_dir_str}/test-1.0-1.atxpkg.zip")).unwrap();
+    atxpkg::clean_cache(cache_dir_str).unwrap();
+    // rollback copies are not offered by the cache repo
+    assert!(
+        list_available(vec![], vec![cache_dir_str.to_string()], false, false)
+            .unwrap()
+            .is_empty()
+    );
+    atxpkg::rollback_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec![repo_dir_str.to_string()],
+        &Options {
+            yes: true,
+            offline: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(installed_packages["test"].version, "1.0-1");
+    assert_eq!(
+        installed_packages["test"]
+            .previous
+            .as_ref()
+            .unwrap()
+            .version,
+        "2.0-1"
+    );
+    assert!(!Path::new(&format!("{dest_dir_str}/test/new")).exists());
+    assert!(!Path::new(&format!("{cache_dir_str}/rollback/test-1.0-1.atxpkg.zip")).exists());
+    assert!(Path::new(&format!("{cache_dir_str}/rollback/test-2.0-1.atxpkg.zip")).exists());
+}
+
+#[test]
+fn test_install_packages_rolls_back_on_failure() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+    let repo_dir = tempfile::Builder::new().tempdir().unwrap();
+    let repo_dir_str = repo_dir.path().to_str().unwrap();
+    std::fs::copy(
+        "./test_data/test-1.0-1.atxpkg.zip",
+        format!("{repo_dir_str}/test-1.0-1.atxpkg.zip"),
+    )
+    .unwrap();
+    std::fs::write(format!("{repo_dir_str}/broken-1.0-1.atxpkg.zip"), "garbage").unwrap();
+
+    let mut installed_packages = HashMap::default();
+    let res = atxpkg::install_packages(
+        vec!["test".to_string(), "broken".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec![repo_dir_str.to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    );
+
+    assert!(res.is_err());
+    assert!(installed_packages.is_empty());
+    assert!(!Path::new(&format!("{dest_dir_str}/test")).exists());
+}
+
+#[test]
+fn test_lock_root_dir() {
+    let root_dir = tempfile::Builder::new().tempdir().unwrap();
+    let root_dir_str = root_dir.path().to_str().unwrap();
+
+    let lock = atxpkg::lock_root_dir(root_dir_str, true, false, "atxpkg update").unwrap();
+    let err = atxpkg::lock_root_dir(root_dir_str, false, false, "atxpkg check")
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "atxpkg is locked by pid {} (atxpkg update), use --wait to wait for it",
+            std::process::id()
+        )
+    );
+    drop(lock);
+
+    let lock1 = atxpkg::lock_root_dir(root_dir_str, false, false, "atxpkg check").unwrap();
+    let lock2 = atxpkg::lock_root_dir(root_dir_str, false, false, "atxpkg check").unwrap();
+    let err = atxpkg::lock_root_dir(root_dir_str, true, false, "atxpkg update")
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("read-only command"));
+    drop((lock1, lock2));
+
+    // holder info of a dead process doesn't block anybody
+    std::fs::write(
+        format!("{root_dir_str}/atxpkg.lock.info"),
+        "999999 atxpkg install x",
+    )
+    .unwrap();
+    let _lock = atxpkg::lock_root_dir(root_dir_str, true, false, "atxpkg update").unwrap();
+    assert_eq!(
+        std::fs::read_to_string(format!("{root_dir_str}/atxpkg.lock.info")).unwrap(),
+        format!("{} atxpkg update", std::process::id())
+    );
+}
+
+#[test]
+fn test_history_undo_transaction() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let tmp_dir_str = tmp_dir.path().to_str().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir_str = cache_dir.path().to_str().unwrap();
+    let history_fn = format!("{tmp_dir_str}/history.jsonl");
+    let repos = vec!["./test_data".to_string()];
+
+    let mut installed_packages = HashMap::default();
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        repos.clone(),
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir_str,
+        Some(&history_fn),
+    )
+    .unwrap();
+    atxpkg::update_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        repos.clone(),
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir_str,
+        Some(&history_fn),
+    )
+    .unwrap();
+    assert!(
+        atxpkg::install_packages(
+            vec!["nonexistent".to_string()],
+            &mut installed_packages,
+            dest_dir_str,
+            repos.clone(),
+            None,
+            &Options {
+                yes: true,
+                ..Default::default()
+            },
+            cache_dir_str,
+            tmp_dir_str,
+            Some(&history_fn)
+        )
+        .is_err()
+    );
+
+    let history = atxpkg::get_history(&history_fn).unwrap();
+    assert_eq!(history.len(), 3);
+    assert_eq!(
+        history[1].changes,
+        vec![PackageChange {
+            name: "test".to_string(),
+            version_old: Some("1.0-1".to_string()),
+            version_new: Some("2.0-1".to_string()),
+        }]
+    );
+    assert!(history[1].error.is_none());
+    assert!(history[2].changes.is_empty());
+    assert!(history[2].error.is_some());
+
+    let undo = |id, installed_packages: &mut HashMap<String, InstalledPackage>| {
+        atxpkg::undo_transaction(
+            id,
+            installed_packages,
+            dest_dir_str,
+            repos.clone(),
+            &Options {
+                yes: true,
+                ..Default::default()
+            },
+            cache_dir_str,
+            tmp_dir_str,
+            &history_fn,
+        )
+    };
+    // the package has been updated since
+    assert!(undo(1, &mut installed_packages).is_err());
+    assert!(undo(3, &mut installed_packages).is_err());
+    undo(2, &mut installed_packages).unwrap();
+    assert_eq!(installed_packages["test"].version, "1.0-1");
+    undo(1, &mut installed_packages).unwrap();
+    assert!(installed_packages.is_empty());
+    assert!(!Path::new(&format!("{dest_dir_str}/test")).exists());
+
+    let history = atxpkg::get_history(&history_fn).unwrap();
+    assert_eq!(history.len(), 5);
+    assert_eq!(history[4].changes[0].version_new, None);
+}
+
+#[test]
+fn test_export_sync_packages() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let tmp_dir_str = tmp_dir.path().to_str().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir_str = cache_dir.path().to_str().unwrap();
+    let manifest_fn = format!("{tmp_dir_str}/manifest.json");
+    let repos = vec!["./test_data".to_string()];
+
+    let mut installed_packages = HashMap::default();
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        repos.clone(),
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir_str,
+        None,
+    )
+    .unwrap();
+    let mut manifest = atxpkg::export_manifest(&installed_packages);
+    manifest.packages.get_mut("test").unwrap().hold = Some(Hold { max_version: None });
+    atxpkg::save_manifest(&manifest, &manifest_fn).unwrap();
+    assert_eq!(atxpkg::get_manifest(&manifest_fn).unwrap(), manifest);
+
+    atxpkg::update_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        repos.clone(),
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir_str,
+        None,
+    )
+    .unwrap();
+    assert_eq!(installed_packages["test"].version, "2.0-1");
+
+    let sync = |manifest: &Manifest, remove, installed_packages: &mut HashMap<_, _>| {
+        atxpkg::sync_packages(
+            manifest,
+            installed_packages,
+            dest_dir_str,
+            repos.clone(),
+            remove,
+            &Options {
+                yes: true,
+                ..Default::default()
+            },
+            cache_dir_str,
+            tmp_dir_str,
+            None,
+        )
+        .unwrap()
+    };
+    assert!(sync(&manifest, false, &mut installed_packages));
+    assert_eq!(installed_packages["test"].version, "1.0-1");
+    assert_eq!(
+        installed_packages["test"].hold,
+        Some(Hold { max_version: None })
+    );
+    assert!(!sync(&manifest, false, &mut installed_packages));
+
+    let empty_manifest = Manifest::default();
+    assert!(!sync(&empty_manifest, false, &mut installed_packages));
+    assert_eq!(installed_packages.len(), 1);
+    assert!(sync(&empty_manifest, true, &mut installed_packages));
+    assert!(installed_packages.is_empty());
+    assert!(!Path::new(&format!("{dest_dir_str}/test")).exists());
+}
+
+#[test]
+fn test_check_packages_repair() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let tmp_dir_str = tmp_dir.path().to_str().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir_str = cache_dir.path().to_str().unwrap();
+    let repos = vec!["./test_data".to_string()];
+
+    let mut installed_packages = HashMap::default();
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        repos.clone(),
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir_str,
+        None,
+    )
+    .unwrap();
+    std::fs::write(format!("{dest_dir_str}/test/unprotected"), "changed\n").unwrap();
+    std::fs::remove_dir(format!("{dest_dir_str}/test/empty")).unwrap();
+    std::fs::write(format!("{dest_dir_str}/test/protected1"), "changed\n").unwrap();
+
+    let mut check = |repair| {
+        atxpkg::check_packages(
+            vec!["test".to_string()],
+            &mut installed_packages,
+            dest_dir_str,
+            repos.clone(),
+            &Options {
+                repair,
+                ..Default::default()
+            },
+            cache_dir_str,
+            tmp_dir_str,
+        )
+    };
+    assert!(check(false).is_err());
+    check(true).unwrap();
+    check(false).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(format!("{dest_dir_str}/test/unprotected")).unwrap(),
+        "1\n"
+    );
+    assert!(Path::new(&format!("{dest_dir_str}/test/empty")).is_dir());
+    // backup files are left alone
+    assert_eq!(
+        std::fs::read_to_string(format!("{dest_dir_str}/test/protected1")).unwrap(),
+        "changed\n"
+    );
+    // stats of the restored file are recorded for the quick check
+    let metadata = std::fs::metadata(format!("{dest_dir_str}/test/unprotected")).unwrap();
+    let file_stat = &installed_packages["test"].file_stats.as_ref().unwrap()["test/unprotected"];
+    assert_eq!(file_stat.size, metadata.len());
+    assert_eq!(
+        file_stat.mtime,
+        metadata
+            .modified()
+            .unwrap()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64()
+    );
+}
+
+#[test]
+fn test_check_packages_repair_failed() {
+    let root_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let repo_dir = tempfile::Builder::new().tempdir().unwrap();
+    let repo_fn = format!(
+        "{}/test-1.0-1.atxpkg.zip",
+        repo_dir.path().to_str().unwrap()
+    );
+    std::fs::copy("./test_data/test-1.0-1.atxpkg.zip", &repo_fn).unwrap();
+    let reporter = std::sync::Arc::new(RecordingReporter::default());
+    let mut atxpkg = Atxpkg::builder(root_dir.path().to_str().unwrap())
+        .prefix(dest_dir_str)
+        .repos(vec![repo_dir.path().to_str().unwrap().to_string()])
+        .reporter(reporter.clone())
+        .open()
+        .unwrap();
+    atxpkg.install(vec!["test-1.0-1".to_string()]).unwrap();
+
+    // the installed version is gone from both the repo and the cache
+    std::fs::remove_file(&repo_fn).unwrap();
+    clean_cache(atxpkg.cache_dir()).unwrap();
+    std::fs::write(format!("{dest_dir_str}/test/unprotected"), "changed\n").unwrap();
+    atxpkg.options_mut().repair = true;
+    assert!(atxpkg.check(vec![]).is_err());
+    let check_reports = reporter.check_reports.lock().unwrap();
+    let failed = check_reports[0]
+        .issues
+        .iter()
+        .filter(|x| x.kind == CheckIssueKind::RepairFailed)
+        .map(|x| x.path.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(failed, vec![format!("{dest_dir_str}/test/unprotected")]);
+}
+
+#[test]
+fn test_check_packages_quick() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let tmp_dir_str = tmp_dir.path().to_str().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir_str = cache_dir.path().to_str().unwrap();
+    let repos = vec!["./test_data".to_string()];
+
+    let mut installed_packages = HashMap::default();
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        repos.clone(),
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir_str,
+        None,
+    )
+    .unwrap();
+    let file_stats = installed_packages["test"].file_stats.clone().unwrap();
+    assert!(file_stats.contains_key("test/unprotected"));
+
+    // same size, original mtime - only a full check notices
+    let fn_unprotected = format!("{dest_dir_str}/test/unprotected");
+    let mtime = std::fs::metadata(&fn_unprotected)
+        .unwrap()
+        .modified()
+        .unwrap();
+    std::fs::write(&fn_unprotected, "2\n").unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&fn_unprotected)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+
+    let mut check = |full| {
+        atxpkg::check_packages(
+            vec!["test".to_string()],
+            &mut installed_packages,
+            dest_dir_str,
+            repos.clone(),
+            &Options {
+                full,
+                ..Default::default()
+            },
+            cache_dir_str,
+            tmp_dir_str,
+        )
+    };
+    check(false).unwrap();
+    assert!(check(true).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_check_packages_metadata() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let tmp_dir_str = tmp_dir.path().to_str().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir_str = cache_dir.path().to_str().unwrap();
+    let repos = vec!["./test_data".to_string()];
+
+    let mut installed_packages = HashMap::default();
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        repos.clone(),
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir_str,
+        None,
+    )
+    .unwrap();
+
+    let mut check = |repair| {
+        atxpkg::check_packages(
+            vec!["test".to_string()],
+            &mut installed_packages,
+            dest_dir_str,
+            repos.clone(),
+            &Options {
+                repair,
+                ..Default::default()
+            },
+            cache_dir_str,
+            tmp_dir_str,
+        )
+    };
+
+    // modified backup file is reported but not an error
+    std::fs::write(format!("{dest_dir_str}/test/protected1"), "changed\n").unwrap();
+    check(false).unwrap();
+
+    let fn_unprotected = format!("{dest_dir_str}/test/unprotected");
+    let mode = std::fs::metadata(&fn_unprotected)
+        .unwrap()
+        .permissions()
+        .mode()
+        & 0o7777;
+    std::fs::set_permissions(&fn_unprotected, std::fs::Permissions::from_mode(0o600)).unwrap();
+    assert!(check(false).is_err());
+    check(true).unwrap();
+    assert_eq!(
+        std::fs::metadata(&fn_unprotected)
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o7777,
+        mode
+    );
+
+    // dir replaced by file can't be repaired
+    std::fs::remove_dir(format!("{dest_dir_str}/test/empty")).unwrap();
+    std::fs::write(format!("{dest_dir_str}/test/empty"), "").unwrap();
+    assert!(check(true).is_err());
+}
+
+#[test]
+fn test_error_kinds() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let tmp_dir_str = tmp_dir.path().to_str().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir_str = cache_dir.path().to_str().unwrap();
+    let repos = vec!["./test_data".to_string()];
+
+    let mut installed_packages = HashMap::default();
+    let install = |packages: Vec<String>, installed_packages: &mut HashMap<_, _>| {
+        atxpkg::install_packages(
+            packages,
+            installed_packages,
+            dest_dir_str,
+            repos.clone(),
+            None,
+            &Options {
+                yes: true,
+                ..Default::default()
+            },
+            cache_dir_str,
+            tmp_dir_str,
+            None,
+        )
+    };
+
+    let err = install(vec!["nonexistent".to_string()], &mut installed_packages).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<atxpkg::Error>(),
+        Some(atxpkg::Error::NotAvailable(_))
+    ));
+
+    install(vec!["test-1.0-1".to_string()], &mut installed_packages).unwrap();
+    let err = install(vec!["test".to_string()], &mut installed_packages).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<atxpkg::Error>(),
+        Some(atxpkg::Error::Conflict(_))
+    ));
+
+    let err = atxpkg::remove_packages(
+        vec!["nonexistent".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        tmp_dir_str,
+        None,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<atxpkg::Error>(),
+        Some(atxpkg::Error::NotInstalled(_))
+    ));
+    assert_eq!(err.to_string(), "package nonexistent not installed");
+
+    std::fs::write(format!("{dest_dir_str}/test/unprotected"), "changed\n").unwrap();
+    let err = atxpkg::check_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        repos.clone(),
+        &Options::default(),
+        cache_dir_str,
+        tmp_dir_str,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<atxpkg::Error>(),
+        Some(atxpkg::Error::Integrity(_))
+    ));
+}
+
+#[test]
+fn test_atxpkg_session() {
+    let root_dir = tempfile::Builder::new().tempdir().unwrap();
+    let root_dir_str = root_dir.path().to_str().unwrap();
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+
+    let open = |read_only| {
+        Atxpkg::builder(root_dir_str)
+            .prefix(dest_dir_str)
+            .repos(vec!["./test_data".to_string()])
+            .read_only(read_only)
+            .yes(true)
+            .open()
+    };
+
+    {
+        let atxpkg = open(false).unwrap();
+        assert!(Path::new(&format!("{root_dir_str}/cache")).exists());
+        assert!(Path::new(&format!("{root_dir_str}/tmp")).exists());
+        assert!(!atxpkg.recovered());
+
+        // the session holds the lock
+        let err = open(true).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<atxpkg::Error>(),
+            Some(atxpkg::Error::Locked(_))
+        ));
+
+        assert!(atxpkg.install(vec!["test-1.0-1".to_string()]).unwrap());
+        assert!(Path::new(&format!("{dest_dir_str}/test/unprotected")).exists());
+        atxpkg.check(vec![]).unwrap();
+        assert_eq!(atxpkg.history().unwrap().len(), 1);
+    }
+
+    {
+        let atxpkg = open(true).unwrap();
+        // nothing left to recover so read-only sessions share the lock
+        let _atxpkg = open(true).unwrap();
+        assert!(
+            std::fs::read_dir(format!("{root_dir_str}/tmp"))
+                .unwrap()
+                .next()
+                .is_none()
+        );
+        assert_eq!(
+            atxpkg.installed_packages().unwrap()["test"].version,
+            "1.0-1"
+        );
+        let owners = atxpkg
+            .owners(vec![format!("{dest_dir_str}/test/unprotected")])
+            .unwrap();
+        assert_eq!(owners[0].owner.as_ref().unwrap().package_name, "test");
+        assert!(atxpkg.remove(vec!["test".to_string()]).is_err());
+    }
+
+    let atxpkg = open(false).unwrap();
+    assert!(atxpkg.remove(vec!["test".to_string()]).unwrap());
+    assert!(atxpkg.installed_packages().unwrap().is_empty());
+    assert!(!Path::new(&format!("{dest_dir_str}/test/unprotected")).exists());
+}
+
+/// Records what a session reports and confirms everything.
+#[derive(Default)]
+struct RecordingReporter {
+    messages: std::sync::Mutex<Vec<String>>,
+    plans: std::sync::Mutex<Vec<Vec<PlannedChange>>>,
+    check_reports: std::sync::Mutex<Vec<CheckReport>>,
+}
+
+impl Reporter for RecordingReporter {
+    fn message(&self, msg: &str) {
+        self.messages.lock().unwrap().push(msg.to_string());
+    }
+
+    fn plan(&self, plan: &[PlannedChange]) {
+        self.plans.lock().unwrap().push(plan.to_vec());
+    }
+
+    fn check_report(&self, report: &CheckReport) {
+        self.check_reports.lock().unwrap().push(report.clone());
+    }
+
+    fn confirm(&self, _prompt: &str, _default: bool) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+}
+
+#[test]
+fn test_atxpkg_session_reporter() {
+    let root_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let reporter = std::sync::Arc::new(RecordingReporter::default());
+
+    let atxpkg = Atxpkg::builder(root_dir.path().to_str().unwrap())
+        .prefix(dest_dir.path().to_str().unwrap())
+        .repos(vec!["./test_data".to_string()])
+        .reporter(reporter.clone())
+        .command("atxpkg install test-1.0-1")
+        .open()
+        .unwrap();
+    // confirmed by the reporter
+    assert!(atxpkg.install(vec!["test-1.0-1".to_string()]).unwrap());
+    assert_eq!(reporter.plans.lock().unwrap().len(), 1);
+    assert!(
+        reporter
+            .messages
+            .lock()
+            .unwrap()
+            .contains(&"test-1.0-1 is now installed".to_string())
+    );
+    assert_eq!(
+        atxpkg.history().unwrap()[0].command,
+        "atxpkg install test-1.0-1"
+    );
+
+    atxpkg
+        .hold(vec!["test".to_string()], Some("2.0".to_string()))
+        .unwrap();
+    let manifest_fn = format!("{}/manifest.json", root_dir.path().to_str().unwrap());
+    let manifest = atxpkg.export(&manifest_fn).unwrap();
+    assert_eq!(manifest, get_manifest(&manifest_fn).unwrap());
+    atxpkg.unhold(vec!["test".to_string()]).unwrap();
+    assert!(atxpkg.installed_packages().unwrap()["test"].hold.is_none());
+
+    // sync restores the hold from the manifest
+    assert!(atxpkg.sync(&manifest, false).unwrap());
+    assert!(atxpkg.installed_packages().unwrap()["test"].hold.is_some());
+    assert_eq!(
+        reporter.plans.lock().unwrap().last().unwrap(),
+        &vec![PlannedChange {
+            action: PlanAction::Hold,
+            name: "test".to_string(),
+            version: "1.0-1".to_string(),
+            name_old: None,
+            version_old: None,
+            detail: Some("2.0".to_string()),
+        }]
+    );
+    assert_eq!(atxpkg.history().unwrap().len(), 2);
+
+    // failed sync reverts the holds as well
+    let mut manifest = Manifest::default();
+    for (name, version) in [("test", "1.0-1"), ("nonexistent", "1.0-1")] {
+        manifest.packages.insert(
+            name.to_string(),
+            ManifestPackage {
+                version: version.to_string(),
+                hold: None,
+                channel: None,
+            },
+        );
+    }
+    assert!(atxpkg.sync(&manifest, false).is_err());
+    assert!(atxpkg.installed_packages().unwrap()["test"].hold.is_some());
+
+    // undo presents the reverse changes as a plan
+    assert!(atxpkg.undo(1).unwrap());
+    assert_eq!(
+        reporter.plans.lock().unwrap().last().unwrap(),
+        &vec![PlannedChange {
+            action: PlanAction::Remove,
+            name: "test".to_string(),
+            version: "1.0-1".to_string(),
+            name_old: None,
+            version_old: None,
+            detail: None,
+        }]
+    );
+    assert!(atxpkg.installed_packages().unwrap().is_empty());
+}
+
+#[test]
+fn test_adopt_installed_package() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let tmp_dir_str = tmp_dir.path().to_str().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir_str = cache_dir.path().to_str().unwrap();
+    let repos = vec!["./test_data".to_string()];
+
+    let install = |installed_packages: &mut HashMap<String, InstalledPackage>, reinstall, adopt| {
+        atxpkg::install_packages(
+            vec!["test-1.0-1".to_string()],
+            installed_packages,
+            dest_dir_str,
+            repos.clone(),
+            None,
+            &Options {
+                reinstall,
+                adopt,
+                yes: true,
+                ..Default::default()
+            },
+            cache_dir_str,
+            tmp_dir_str,
+            None,
+        )
+    };
+
+    let mut installed_packages = HashMap::default();
+    install(&mut installed_packages, false, true).unwrap();
+    let installed_packages_orig = installed_packages.clone();
+
+    // reinstall goes through update which has nothing to adopt
+    let err = install(&mut installed_packages, true, true).unwrap_err();
+    assert!(err.to_string().contains("--adopt"));
+    assert_eq!(installed_packages, installed_packages_orig);
+
+    let err = atxpkg::upstall_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        repos.clone(),
+        None,
+        &Options {
+            adopt: true,
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir_str,
+        None,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("--adopt"));
+    assert_eq!(installed_packages, installed_packages_orig);
+}
+
+#[test]
+fn test_origin_repo_of_cached_package() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir_str = cache_dir.path().to_str().unwrap();
+    std::fs::copy(
+        "./test_data/test-1.0-1.atxpkg.zip",
+        format!("{cache_dir_str}/test-1.0-1.atxpkg.zip"),
+    )
+    .unwrap();
+    let repos = vec![cache_dir_str.to_string(), "./test_data".to_string()];
+
+    // only the cached copy known
+    let details = atxpkg::get_package_details(
+        "test-1.0-1",
+        &HashMap::default(),
+        dest_dir_str,
+        vec![cache_dir_str.to_string()],
+        false,
+        false,
+        cache_dir_str,
+    )
+    .unwrap();
+    assert_eq!(details.repo, None);
+
+    let mut installed_packages = HashMap::default();
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        repos,
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir_str,
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(
+        installed_packages["test"].repo,
+        Some("./test_data".to_string())
+    );
+}
+
+#[test]
+fn test_list_installed_json() {
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let cache_dir = tempfile::Builder::new().tempdir().unwrap();
+
+    let mut installed_packages = HashMap::default();
+    atxpkg::install_packages(
+        vec!["test-1.0-1".to_string()],
+        &mut installed_packages,
+        dest_dir_str,
+        vec!["./test_data".to_string()],
+        None,
+        &Options {
+            yes: true,
+            ..Default::default()
+        },
+        cache_dir.path().to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+        None,
+    )
+    .unwrap();
+    atxpkg::hold_packages(
+        vec!["test".to_string()],
+        &mut installed_packages,
+        Some("2.0".to_string()),
+    )
+    .unwrap();
+
+    let installed = list_installed(&installed_packages, Some(Channel::Dev));
+    assert_eq!(
+        serde_json::to_value(&installed).unwrap(),
+        serde_json::json!([{
+            "name": "test",
+            "version": "1.0-1",
+            "hold": {"max_version": "2.0"},
+            "channel": "dev",
+        }])
+    );
+}
+
+#[test]
+fn test_json_output() {
+    let root_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir = tempfile::Builder::new().tempdir().unwrap();
+    let dest_dir_str = dest_dir.path().to_str().unwrap();
+    let repo_dir = tempfile::Builder::new().tempdir().unwrap();
+    let repo_dir_str = repo_dir.path().to_str().unwrap();
+    for (src, dst) in [
+        ("test-1.0-1", "test-1.0-1"),
+        ("test-2.0-1", "test-2.0-1"),
+        ("test-1.0-1", "other-1.0-1"),
+    ] {
+        std::fs::copy(
+            format!("./test_data/{src}.atxpkg.zip"),
+            format!("{repo_dir_str}/{dst}.atxpkg.zip"),
+        )
+        .unwrap();
+    }
+    let reporter = std::sync::Arc::new(RecordingReporter::default());
+    let mut atxpkg = Atxpkg::builder(root_dir.path().to_str().unwrap())
+        .prefix(dest_dir_str)
+        .repos(vec![repo_dir_str.to_string()])
+        .reporter(reporter.clone())
+        .open()
+        .unwrap();
+    atxpkg.install(vec!["test-1.0-1".to_string()]).unwrap();
+
+    // upstall plans the installs and the updates at once
+    reporter.plans.lock().unwrap().clear();
+    atxpkg.options_mut().download_only = true;
+    atxpkg
+        .upstall(vec!["other".to_string(), "test".to_string()])
+        .unwrap();
+    let plans = reporter.plans.lock().unwrap().clone();
+    assert_eq!(plans.len(), 1);
+    assert_eq!(
+        serde_json::to_value(&plans[0]).unwrap(),
+        serde_json::json!([
+            {
+                "action": "download",
+                "name": "other",
+                "version": "1.0-1",
+                "name_old": null,
+                "version_old": null,
+                "detail": null,
+            },
+            {
+                "action": "download",
+                "name": "test",
+                "version": "2.0-1",
+                "name_old": "test",
+                "version_old": "1.0-1",
+                "detail": null,
+            },
+        ])
+    );
+
+    std::fs::remove_file(format!("{dest_dir_str}/test/unprotected")).unwrap();
+    assert!(atxpkg.check(vec![]).is_err());
+    let check_reports = reporter.check_reports.lock().unwrap().clone();
+    assert_eq!(
+        serde_json::to_value(&check_reports[0]).unwrap(),
+        serde_json::json!({
+            "issues": [{
+                "package": "test",
+                "path": format!("{dest_dir_str}/test/unprotected"),
+                "kind": "missing",
+                "detail": null,
+            }],
+            "summary": {
+                "missing": 1,
+                "wrong_type": 0,
+                "checksum_difference": 0,
+                "permission_difference": 0,
+                "modified_config": 0,
+                "restored": 0,
+                "errors": 1,
+            },
+        })
+    );
+
+    assert_eq!(
+        serde_json::to_value(atxpkg.list_available(vec![]).unwrap()).unwrap(),
+        serde_json::json!([
+            {"name": "other", "version": null},
+            {"name": "test", "version": null},
+        ])
+    );
+    assert_eq!(
+        serde_json::to_value(atxpkg.list_available(vec!["test".to_string()]).unwrap()).unwrap(),
+        serde_json::json!([
+            {"name": "test", "version": "1.0-1"},
+            {"name": "test", "version": "2.0-1"},
+        ])
+    );
+
+    assert_eq!(
+        serde_json::to_value(
+            atxpkg
+                .owners(vec![
+                    "test/unprotected".to_string(),
+                    "test/unknown".to_string()
+                ])
+                .unwrap()
+        )
+        .unwrap(),
+        serde_json::json!([
+            {
+                "path": "test/unprotected",
+                "owner": {
+                    "package_name": "test",
+                    "version": "1.0-1",
+                    "is_dir": false,
+                    "is_backup": false,
+                    "is_modified": false,
+                    "is_missing": true,
+                },
+            },
+            {"path": "test/unknown", "owner": null},
+        ])
+    );
 }